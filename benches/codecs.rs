@@ -0,0 +1,99 @@
+// Compares the wire codecs on chat histories sent as RESULT_CHAT_MESSAGES.
+// Run with: cargo bench --bench codecs
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use serde_json::json;
+use server::codec::Codec;
+use yapping_core::{client_server_coms::{Query, Response, ServerMessage, ServerMessageContent}, l3gion_rust::UUID, message::Message};
+
+const HISTORY_SIZES: &[usize] = &[10, 100, 1_000, 10_000];
+const ROUNDS: u32 = 50;
+// Decoding limit, large enough for the biggest history.
+const LIMIT: usize = 64 * 1024 * 1024;
+
+const WORDS: &[&str] = &[
+    "hey", "are", "you", "coming", "tonight", "lol", "yeah", "sure", "what", "time",
+    "the", "game", "starts", "at", "eight", "bring", "snacks", "ok", "see", "you",
+    "later", "did", "you", "see", "that", "video", "i", "sent", "haha", "no", "way",
+];
+
+fn main() {
+    let codecs = [Codec::BINCODE, Codec::MSGPACK, Codec::CBOR, Codec::JSON];
+
+    println!("{:>8} {:>16} {:>12} {:>12} {:>12} {:>12} {:>12} {:>12}", "messages", "codec", "bytes", "encode", "decode", "zstd bytes", "zstd encode", "zstd decode");
+    for &size in HISTORY_SIZES {
+        let msg = chat_history(size);
+
+        for codec in codecs {
+            let frame = codec.encode(&msg, None).unwrap();
+            let encode = time(|| codec.encode(black_box(&msg), None).unwrap());
+            let decode = time(|| codec.decode(black_box(&frame), LIMIT, false).unwrap());
+
+            // Text frames are never compressed.
+            let zstd = codec.is_binary().then(|| {
+                let frame = codec.encode(&msg, Some(0)).unwrap();
+                let encode = time(|| codec.encode(black_box(&msg), Some(0)).unwrap());
+                let decode = time(|| codec.decode(black_box(&frame), LIMIT, true).unwrap());
+
+                (frame.len(), encode, decode)
+            });
+
+            match zstd {
+                Some((bytes, zstd_encode, zstd_decode)) => println!(
+                    "{:>8} {:>16} {:>12} {:>12?} {:>12?} {:>12} {:>12?} {:>12?}",
+                    size, codec.subprotocol(), frame.len(), encode, decode, bytes, zstd_encode, zstd_decode,
+                ),
+                None => println!(
+                    "{:>8} {:>16} {:>12} {:>12?} {:>12?} {:>12} {:>12} {:>12}",
+                    size, codec.subprotocol(), frame.len(), encode, decode, "-", "-", "-",
+                ),
+            }
+        }
+    }
+}
+
+// Average over ROUNDS, after one warm up run.
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    black_box(f());
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        black_box(f());
+    }
+
+    start.elapsed() / ROUNDS
+}
+
+// Two people talking, short messages with the odd long one, like a real chat.
+fn chat_history(size: usize) -> ServerMessage {
+    let mut seed = 0x2545_F491_4F6C_DD1D_u64;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 7;
+        seed ^= seed << 17;
+        seed
+    };
+
+    let senders = [UUID::generate(), UUID::generate()];
+    let messages = (0..size)
+        .map(|i| {
+            let words = if next() % 10 == 0 { 20 + next() % 40 } else { 1 + next() % 8 };
+            let content = (0..words)
+                .map(|_| WORDS[(next() % WORDS.len() as u64) as usize])
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            serde_json::from_value::<Message>(json!({
+                "uuid": UUID::generate(),
+                "sender": senders[(next() % 2) as usize],
+                "content": content,
+                "timestamp": 1_700_000_000_000 + i as i64 * 45_000,
+                "read": i + 5 < size,
+            }))
+            .unwrap()
+        })
+        .collect();
+
+    ServerMessage::new(UUID::generate(), ServerMessageContent::RESPONSE(Response::OK_QUERY(Query::RESULT_CHAT_MESSAGES(messages))))
+}
@@ -0,0 +1,202 @@
+use std::io::{BufRead, Write};
+
+use mongodb::bson::{Bson, DateTime, Document};
+use server::{audit::{self, AuditAction, AuditEvent}, mongo_db::{MongoDB, MongoDBClient}};
+use yapping_core::l3gion_rust::StdError;
+
+const USAGE: &str = "Usage: yapping_admin <command>
+
+Commands:
+    users [search]                          List users, optionally filtered by id, email or tag
+    reset-password <email>                  Replace the stored password, in the same form clients send it,
+                                            read from stdin
+    ban <user id> [reason]                  Ban an account
+    unban <user id>                         Lift a ban
+    suspend <user id> <hours> [reason]      Suspend an account for a number of hours
+    unsuspend <user id>                     Lift a suspension
+    delete-chat <chat id>                   Delete a chat and all of its messages
+    notifications <user id>                 Print every stored notification of a user
+    stats                                   Print server-wide stats
+    quarantine                              List quarantined documents
+    quarantine repair <id> <json file>      Put a fixed document back in place of the original
+    audit [user] [--since <time>] [--until <time>]
+                                            Print the audit trail, optionally only entries where the user id or
+                                            email is the actor or the target, times in RFC 3339";
+
+#[tokio::main]
+async fn main() -> Result<(), StdError> {
+    // Warnings from the shared modules, kept off stdout.
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let args = args.iter().map(|arg| arg.as_str()).collect::<Vec<_>>();
+
+    let db = MongoDBClient::connect().await?.get_database();
+
+    match args.as_slice() {
+        ["users"] => print_users(&db, None).await?,
+        ["users", search] => print_users(&db, Some(*search)).await?,
+        ["reset-password", email] => {
+            let password = read_password()?;
+            let found = db.reset_password(email, &password).await?;
+            record_admin_action(&db, AuditAction::PASSWORD_RESET, email, found).await;
+            report(found, "Password reset", "No user with that email");
+        }
+        ["ban", user_id, reason @ ..] => {
+            let found = db.set_banned(user_id, true, &reason.join(" ")).await?;
+            record_admin_action(&db, AuditAction::BAN, user_id, found).await;
+            report(found, "User banned", "User not found");
+        }
+        ["unban", user_id] => {
+            let found = db.set_banned(user_id, false, "").await?;
+            record_admin_action(&db, AuditAction::UNBAN, user_id, found).await;
+            report(found, "User unbanned", "User not found");
+        }
+        ["suspend", user_id, hours, reason @ ..] => {
+            let hours = hours.parse::<i64>().map_err(|_| "Hours must be a whole number!")?;
+            let until = DateTime::from_millis(DateTime::now().timestamp_millis() + hours * 60 * 60 * 1000);
+            let found = db.set_suspended(user_id, Some(until), &reason.join(" ")).await?;
+            record_admin_action(&db, AuditAction::SUSPEND, user_id, found).await;
+            report(found, "User suspended", "User not found");
+        }
+        ["unsuspend", user_id] => {
+            let found = db.set_suspended(user_id, None, "").await?;
+            record_admin_action(&db, AuditAction::UNSUSPEND, user_id, found).await;
+            report(found, "Suspension lifted", "User not found");
+        }
+        ["delete-chat", chat_id] => {
+            let found = db.force_delete_chat(chat_id).await?;
+            record_admin_action(&db, AuditAction::CHAT_DELETE, chat_id, found).await;
+            report(found, "Chat deleted", "Chat not found");
+        }
+        ["notifications", user_id] => for notification in db.get_raw_notifications(user_id).await? {
+            println!("{notification}");
+        },
+        ["stats"] => {
+            let stats = db.get_stats().await?;
+            println!("Users:         {} ({} banned)", stats.users, stats.banned_users);
+            println!("Chats:         {}", stats.chats);
+            println!("Messages:      {}", stats.messages);
+            println!("Notifications: {}", stats.notifications);
+            println!("Quarantined:   {}", stats.quarantined);
+        }
+        ["quarantine"] | ["quarantine", "list"] => for entry in db.get_quarantined().await? {
+            println!(
+                "{} ({}): {}\n    {}",
+                entry.get_str("_id").unwrap_or_default(),
+                entry.get_datetime("quarantined_at").map(|d| d.to_string()).unwrap_or_default(),
+                entry.get_str("error").unwrap_or_default(),
+                entry.get_document("document").map(|d| d.to_string()).unwrap_or_default(),
+            );
+        },
+        ["quarantine", "repair", id, json_file] => {
+            let json = serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(json_file)?)?;
+            let repaired = match Bson::try_from(json)? {
+                Bson::Document(document) => document,
+                _ => return Err("The repaired document must be a JSON object!".into()),
+            };
+
+            db.repair_quarantined(id, repaired).await?;
+            record_admin_action(&db, AuditAction::QUARANTINE_REPAIR, id, true).await;
+            println!("Document repaired");
+        }
+        ["audit", filters @ ..] => print_audit(&db, filters).await?,
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
+
+async fn print_users(db: &MongoDB, search: Option<&str>) -> Result<(), StdError> {
+    for user in db.find_users(search).await? {
+        println!(
+            "{:<40} {:<24} {:<32} {}",
+            field(&user, "_id"),
+            field(&user, "tag"),
+            field(&user, "email"),
+            if user.get_bool("banned").unwrap_or(false) { "BANNED" } 
+            else if user.get_datetime("suspended_until").is_ok_and(|until| *until > DateTime::now()) { "SUSPENDED" } 
+            else { "" },
+        );
+    }
+
+    Ok(())
+}
+
+async fn print_audit(db: &MongoDB, filters: &[&str]) -> Result<(), StdError> {
+    let mut user = None;
+    let mut since = None;
+    let mut until = None;
+
+    let mut filters = filters.iter();
+    while let Some(filter) = filters.next() {
+        match *filter {
+            "--since" => since = Some(parse_time(filters.next())?),
+            "--until" => until = Some(parse_time(filters.next())?),
+            _ if user.is_none() => user = Some(*filter),
+            _ => return Err(USAGE.into()),
+        }
+    }
+
+    for event in db.get_audit_events(user, since, until).await? {
+        println!(
+            "{} {:<18} {:<40} {:<40} {:<16} {}",
+            event.get_datetime("timestamp").map(|d| d.to_string()).unwrap_or_default(),
+            field(&event, "action"),
+            field(&event, "actor"),
+            field(&event, "target"),
+            field(&event, "address"),
+            field(&event, "outcome"),
+        );
+    }
+
+    Ok(())
+}
+
+// Kept off argv, where other users on the machine and the shell history would see it.
+fn read_password() -> Result<String, StdError> {
+    eprint!("New password: ");
+    std::io::stderr().flush()?;
+
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("The password can't be empty!".into());
+    }
+
+    Ok(password.to_string())
+}
+
+fn parse_time(time: Option<&&str>) -> Result<DateTime, StdError> {
+    let time = time.ok_or("Missing time, expected RFC 3339 like 2024-01-31T12:00:00Z")?;
+    DateTime::parse_rfc3339_str(time).map_err(|e| format!("Invalid time {time}: {e}").into())
+}
+
+// Admin actions are recorded as done by whoever ran the tool on this machine.
+async fn record_admin_action(db: &MongoDB, action: AuditAction, target: &str, found: bool) {
+    let user = std::env::var("USER").unwrap_or_else(|_| "unknown".to_string());
+
+    audit::record(db, AuditEvent {
+        action,
+        actor: Some(format!("admin:{user}")),
+        target: Some(target.to_string()),
+        address: None,
+        outcome: if found { "success" } else { "not found" }.to_string(),
+    }).await;
+}
+
+fn field(document: &Document, key: &str) -> String {
+    match document.get(key) {
+        Some(Bson::String(value)) => value.clone(),
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+fn report(found: bool, ok: &str, not_found: &str) {
+    println!("{}", if found { ok } else { not_found });
+}
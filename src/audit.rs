@@ -0,0 +1,99 @@
+use std::net::IpAddr;
+
+use mongodb::bson::{doc, DateTime, Document};
+use tracing::error;
+
+use crate::{mongo_db::MongoDB, server_error::ServerError};
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    SIGN_UP,
+    LOGIN,
+    LOGIN_LOCKOUT,
+    TAG_CHANGE,
+    FRIEND_ADD,
+    FRIEND_REMOVE,
+    CHAT_CREATE,
+    CHAT_DELETE,
+    // Only recorded by yapping_admin.
+    BAN,
+    UNBAN,
+    SUSPEND,
+    UNSUSPEND,
+    PASSWORD_RESET,
+    QUARANTINE_REPAIR,
+}
+impl AuditAction {
+    // Stored in the database, never rename one.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            AuditAction::SIGN_UP => "sign_up",
+            AuditAction::LOGIN => "login",
+            AuditAction::LOGIN_LOCKOUT => "login_lockout",
+            AuditAction::TAG_CHANGE => "tag_change",
+            AuditAction::FRIEND_ADD => "friend_add",
+            AuditAction::FRIEND_REMOVE => "friend_remove",
+            AuditAction::CHAT_CREATE => "chat_create",
+            AuditAction::CHAT_DELETE => "chat_delete",
+            AuditAction::BAN => "ban",
+            AuditAction::UNBAN => "unban",
+            AuditAction::SUSPEND => "suspend",
+            AuditAction::UNSUSPEND => "unsuspend",
+            AuditAction::PASSWORD_RESET => "password_reset",
+            AuditAction::QUARANTINE_REPAIR => "quarantine_repair",
+        }
+    }
+}
+
+// One entry of the append-only audit trail.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub action: AuditAction,
+    // Who did it, a user id, or "admin:<system user>" for yapping_admin.
+    pub actor: Option<String>,
+    // A user id, email, chat id or address, depending on the action.
+    pub target: Option<String>,
+    pub address: Option<IpAddr>,
+    pub outcome: String,
+}
+impl AuditEvent {
+    // The outcome is "success", or the error the action failed with.
+    pub(crate) fn with_result<T>(
+        action: AuditAction,
+        actor: Option<String>,
+        target: Option<String>,
+        address: Option<IpAddr>,
+        result: &Result<T, ServerError>,
+    ) -> Self
+    {
+        Self {
+            action,
+            actor,
+            target,
+            address,
+            outcome: match result {
+                Ok(_) => "success".to_string(),
+                Err(e) => e.to_string(),
+            },
+        }
+    }
+
+    pub(crate) fn to_document(&self) -> Document {
+        doc! {
+            "action": self.action.name(),
+            "actor": self.actor.clone(),
+            "target": self.target.clone(),
+            "address": self.address.map(|a| a.to_string()),
+            "outcome": self.outcome.clone(),
+            "timestamp": DateTime::now(),
+        }
+    }
+}
+
+// A missing audit entry is logged but never fails the action it describes.
+pub async fn record(mongo_db: &MongoDB, event: AuditEvent) {
+    if let Err(e) = mongo_db.insert_audit_event(&event).await {
+        error!("In audit::record: {e} ({event:?})");
+    }
+}
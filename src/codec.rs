@@ -0,0 +1,192 @@
+use tokio_tungstenite::tungstenite::Message as TkMessage;
+use yapping_core::{bincode::Options, client_server_coms::ServerMessage, l3gion_rust::StdError};
+
+// First byte of every binary frame once compression is negotiated.
+const UNCOMPRESSED: u8 = 0;
+const ZSTD: u8 = 1;
+const ZSTD_LEVEL: i32 = 3;
+
+// How ServerMessages are written on the wire, picked per connection through the Sec-WebSocket-Protocol header.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    // Binary frames, the native clients.
+    #[default]
+    BINCODE,
+    // Binary frames, structs as maps so clients don't need the field order.
+    MSGPACK,
+    CBOR,
+    // Text frames, for browsers and scripts.
+    JSON,
+}
+impl Codec {
+    pub fn subprotocol(&self) -> &'static str {
+        match self {
+            Codec::BINCODE => "yapping.bincode",
+            Codec::MSGPACK => "yapping.msgpack",
+            Codec::CBOR => "yapping.cbor",
+            Codec::JSON => "yapping.json",
+        }
+    }
+
+    // The first one we speak, in the client's order of preference.
+    pub(crate) fn from_subprotocols(header: &str) -> Option<Self> {
+        header.split(',')
+            .map(str::trim)
+            .find_map(|name| [Codec::BINCODE, Codec::MSGPACK, Codec::CBOR, Codec::JSON].into_iter().find(|codec| codec.subprotocol() == name))
+    }
+
+    // Only binary frames can be compressed.
+    pub fn is_binary(&self) -> bool {
+        !matches!(self, Codec::JSON)
+    }
+
+    // With a threshold, binary frames carry a leading flag byte and payloads of at least that size are compressed.
+    pub fn encode(&self, msg: &ServerMessage, compress_from: Option<usize>) -> Result<TkMessage, StdError> {
+        let bytes = match self {
+            Codec::BINCODE => yapping_core::bincode::serialize(msg)?,
+            Codec::MSGPACK => rmp_serde::to_vec_named(msg)?,
+            Codec::CBOR => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(msg, &mut bytes)?;
+                bytes
+            },
+            Codec::JSON => return Ok(TkMessage::Text(serde_json::to_string(msg)?)),
+        };
+
+        let Some(threshold) = compress_from else { return Ok(TkMessage::Binary(bytes)) };
+
+        let frame = if bytes.len() >= threshold {
+            let mut frame = vec![ZSTD];
+            frame.extend(zstd::bulk::compress(&bytes, ZSTD_LEVEL)?);
+            frame
+        } else {
+            let mut frame = Vec::with_capacity(bytes.len() + 1);
+            frame.push(UNCOMPRESSED);
+            frame.extend(bytes);
+            frame
+        };
+
+        Ok(TkMessage::Binary(frame))
+    }
+
+    // Fails on frames of the other codec's kind too.
+    pub fn decode(&self, frame: &TkMessage, limit: usize, compressed: bool) -> Result<ServerMessage, StdError> {
+        let bytes = match (self, frame) {
+            (Codec::JSON, TkMessage::Text(text)) => return Ok(serde_json::from_str::<ServerMessage>(text)?),
+            (Codec::BINCODE | Codec::MSGPACK | Codec::CBOR, TkMessage::Binary(bytes)) => bytes,
+            _ => return Err(format!("Unexpected frame for {}", self.subprotocol()).into()),
+        };
+
+        let decompressed;
+        let bytes = match (compressed, bytes.split_first()) {
+            (false, _) => bytes.as_slice(),
+            (true, Some((&UNCOMPRESSED, bytes))) => bytes,
+            (true, Some((&ZSTD, bytes))) => {
+                // The limit also holds after decompression, against zip bombs.
+                decompressed = zstd::bulk::decompress(bytes, limit)?;
+                decompressed.as_slice()
+            },
+            (true, _) => return Err("Invalid compression flag".into()),
+        };
+
+        match self {
+            Codec::BINCODE => Ok(bincode_options(limit).deserialize::<ServerMessage>(bytes)?),
+            Codec::MSGPACK => Ok(rmp_serde::from_slice::<ServerMessage>(bytes)?),
+            Codec::CBOR => Ok(ciborium::from_reader::<ServerMessage, _>(bytes)?),
+            Codec::JSON => Err("JSON is only sent in text frames".into()),
+        }
+    }
+}
+
+// Same layout as bincode::serialize, but length prefixes can't make it allocate past the limit.
+fn bincode_options(limit: usize) -> impl Options {
+    yapping_core::bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use yapping_core::{client_server_coms::{Query, Response, ServerMessageContent}, l3gion_rust::UUID};
+
+    use super::*;
+
+    const CODECS: [Codec; 4] = [Codec::BINCODE, Codec::MSGPACK, Codec::CBOR, Codec::JSON];
+    const LIMIT: usize = 1024 * 1024;
+
+    fn message(words: usize) -> ServerMessage {
+        let error = vec!["yap"; words].join(" ");
+        ServerMessage::new(UUID::generate(), ServerMessageContent::RESPONSE(Response::Err(error)))
+    }
+
+    // ServerMessage has no PartialEq.
+    fn assert_same(a: &ServerMessage, b: &ServerMessage) {
+        assert_eq!(format!("{a:?}"), format!("{b:?}"));
+    }
+
+    #[test]
+    fn every_codec_round_trips() {
+        let msg = message(10);
+        for codec in CODECS {
+            let frame = codec.encode(&msg, None).unwrap();
+            assert_eq!(frame.is_binary(), codec.is_binary());
+            assert_same(&codec.decode(&frame, LIMIT, false).unwrap(), &msg);
+        }
+
+        let msg = ServerMessage::new(UUID::generate(), ServerMessageContent::RESPONSE(Response::OK_QUERY(Query::RESULT_USER(vec![]))));
+        for codec in CODECS {
+            let frame = codec.encode(&msg, None).unwrap();
+            assert_same(&codec.decode(&frame, LIMIT, false).unwrap(), &msg);
+        }
+    }
+
+    #[test]
+    fn compressed_frames_round_trip() {
+        for codec in CODECS.into_iter().filter(Codec::is_binary) {
+            for msg in [message(1), message(1000)] {
+                let frame = codec.encode(&msg, Some(256)).unwrap();
+                assert_same(&codec.decode(&frame, LIMIT, true).unwrap(), &msg);
+            }
+        }
+    }
+
+    #[test]
+    fn large_frames_are_compressed() {
+        let TkMessage::Binary(small) = Codec::BINCODE.encode(&message(1), Some(256)).unwrap() else { panic!("Expected a binary frame") };
+        let TkMessage::Binary(large) = Codec::BINCODE.encode(&message(1000), Some(256)).unwrap() else { panic!("Expected a binary frame") };
+
+        assert_eq!(small[0], UNCOMPRESSED);
+        assert_eq!(large[0], ZSTD);
+        assert!(large.len() < 1000);
+    }
+
+    #[test]
+    fn frames_of_the_other_kind_are_rejected() {
+        let msg = message(1);
+        let text = Codec::JSON.encode(&msg, None).unwrap();
+        let binary = Codec::BINCODE.encode(&msg, None).unwrap();
+
+        assert!(Codec::BINCODE.decode(&text, LIMIT, false).is_err());
+        assert!(Codec::JSON.decode(&binary, LIMIT, false).is_err());
+    }
+
+    #[test]
+    fn decoding_stops_at_the_limit() {
+        let msg = message(1000);
+        assert!(Codec::BINCODE.decode(&Codec::BINCODE.encode(&msg, None).unwrap(), 100, false).is_err());
+
+        // Against zip bombs, whatever the codec.
+        for codec in CODECS.into_iter().filter(Codec::is_binary) {
+            assert!(codec.decode(&codec.encode(&msg, Some(0)).unwrap(), 100, true).is_err());
+        }
+    }
+
+    #[test]
+    fn subprotocols_are_picked_in_the_clients_order() {
+        assert_eq!(Codec::from_subprotocols("yapping.json, yapping.bincode"), Some(Codec::JSON));
+        assert_eq!(Codec::from_subprotocols("chat, yapping.cbor"), Some(Codec::CBOR));
+        assert_eq!(Codec::from_subprotocols("chat"), None);
+    }
+}
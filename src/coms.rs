@@ -1,43 +1,100 @@
+use std::{collections::HashSet, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Instant};
+
 use futures::{stream::SplitSink, SinkExt};
 use tokio::{net::TcpStream, sync::mpsc::{Receiver, Sender}};
 use tokio_tungstenite::WebSocketStream;
-use yapping_core::{client_server_coms::{ComsManager, Modification, Notification, NotificationType, Query, Response, ServerMessage, ServerMessageContent, Session}, l3gion_rust::{sllog::{error, info, warn}, StdError, UUID}};
-use tokio_tungstenite::tungstenite::Message as TkMessage;
-use crate::{mongo_db::MongoDB, notification_manager::NotificationManagerMessage};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use yapping_core::{client_server_coms::{ComsManager, Modification, Notification, NotificationType, Query, Response, ServerMessage, ServerMessageContent, Session}, user::User, l3gion_rust::{StdError, UUID}};
+use tokio_tungstenite::tungstenite::{protocol::{frame::coding::CloseCode, CloseFrame}, Error as TkError, Message as TkMessage};
+use crate::{audit::{self, AuditAction, AuditEvent}, codec::Codec, config::ServerConfig, mongo_db::MongoDB, logging::Redacted, login_guard::{self, LoginGuard}, metrics::Metrics, policy, protocol::{self, Hello, Protocol, UPGRADE_REQUIRED}, rate_limiter::{MessageKind, RateLimiter}, server_error::ServerError, notification_manager::{NotificationManagerMessage, UserConnection}};
 
 macro_rules! create_response {
+    // Errors are logged in full and only their code and safe message reach the client.
+    (Response::Err, $msg_uuid:expr, $error:expr) => {{
+        let error = ServerError::from($error);
+        error.log();
+        ServerMessage::new($msg_uuid, ServerMessageContent::RESPONSE(Response::Err(error.to_client_string())))
+    }};
     ($response_type:expr, $msg_uuid:expr, $content:expr) => {
         ServerMessage::new($msg_uuid, ServerMessageContent::RESPONSE($response_type($content)))
     };
 }
 
 pub(crate) struct Coms {
+    address: SocketAddr,
     user_uuid: UUID,
+    // The connection span, its user and tag fields are filled in on login.
+    span: Span,
+    codec: Codec,
+    // Set by the first frame, a HELLO or a legacy client's first message.
+    protocol: Option<Protocol>,
     notification_sender: Sender<Notification>,
     notification_receiver: Receiver<Notification>,
+    disconnect_sender: Sender<String>,
+    disconnect_receiver: Receiver<String>,
+    closed: bool,
+    shut_down: bool,
+    rate_limit_violations: u32,
+    decode_failures: u32,
+
+    connected_at: Instant,
+    // Any frame from the client counts, not only pongs.
+    last_activity: Instant,
+    last_ping: Instant,
+    missed_heartbeats: u32,
     
     notification_manager_sender: Sender<(UUID, NotificationManagerMessage)>,
 
+    config: ServerConfig,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    login_guard: Arc<Mutex<LoginGuard>>,
+    metrics: Arc<Metrics>,
     mongo_db: MongoDB,
     manager: ComsManager,
     write: SplitSink<WebSocketStream<TcpStream>, TkMessage>,
 }
 impl Coms {
     pub(crate) fn new(
+        address: SocketAddr,
+        codec: Codec,
+        config: ServerConfig,
+        rate_limiter: Arc<Mutex<RateLimiter>>,
+        login_guard: Arc<Mutex<LoginGuard>>,
+        metrics: Arc<Metrics>,
         mongo_db: MongoDB,
         notification_manager_sender: Sender<(UUID, NotificationManagerMessage)>,
         write: SplitSink<WebSocketStream<TcpStream>, TkMessage>,
     ) -> Self 
     {
-        let (notification_sender, notification_receiver) = tokio::sync::mpsc::channel(100);
+        let (notification_sender, notification_receiver) = tokio::sync::mpsc::channel(config.notification_queue_depth.max(1));
+        let (disconnect_sender, disconnect_receiver) = tokio::sync::mpsc::channel(1);
 
         Self {
+            address,
             user_uuid: UUID::default(),
+            span: Span::current(),
+            codec,
+            protocol: None,
             notification_sender,
             notification_receiver,
+            disconnect_sender,
+            disconnect_receiver,
+            closed: false,
+            shut_down: false,
+            rate_limit_violations: 0,
+            decode_failures: 0,
+
+            connected_at: Instant::now(),
+            last_activity: Instant::now(),
+            last_ping: Instant::now(),
+            missed_heartbeats: 0,
             
             notification_manager_sender,
 
+            config,
+            rate_limiter,
+            login_guard,
+            metrics,
             mongo_db,
             manager: ComsManager::default(),
             write,
@@ -45,10 +102,20 @@ impl Coms {
     }
     
     pub(crate) async fn update(&mut self) -> Result<(), StdError> {
+        if let Ok(reason) = self.disconnect_receiver.try_recv() {
+            return self.close(CloseCode::Policy, reason).await;
+        }
+
+        self.heartbeat().await?;
+        if self.closed {
+            return Ok(());
+        }
+
         self.manager.update();
 
         for msg in self.manager.to_retry() {
-            warn!("Sending to_retry: {:?}", msg);
+            warn!("Sending to_retry: {:?}", Redacted(&msg));
+            self.metrics.message_retried();
             self.send_msg(Some(msg)).await?;
         }
     
@@ -64,223 +131,211 @@ impl Coms {
         Ok(())
     }
 
-    pub(crate) async fn shutdown(&self) -> Result<(), tokio::sync::mpsc::error::SendError<(UUID, NotificationManagerMessage)>> {
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    pub(crate) async fn shutdown(&mut self) -> Result<(), tokio::sync::mpsc::error::SendError<(UUID, NotificationManagerMessage)>> {
+        self.shut_down = true;
         self.notification_manager_sender.send((
             self.user_uuid,
-            NotificationManagerMessage::USER_OFFLINE
+            NotificationManagerMessage::USER_OFFLINE(self.notification_sender.clone())
         ))
         .await
     }
 
-    pub(crate) async fn receive_msg(&mut self, msg: Vec<u8>) -> Result<(), StdError> {
-        let msg = deserialize(msg)?;
-        info!("Received Message: {:#?}", msg);
+    pub(crate) fn record_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub(crate) async fn receive_frame(&mut self, frame: TkMessage) -> Result<(), StdError> {
+        match frame {
+            TkMessage::Text(text) if self.protocol.is_none() && Hello::is_hello(&text) => self.receive_hello(&text).await,
+            TkMessage::Binary(_) | TkMessage::Text(_) => {
+                if self.protocol.is_none() {
+                    info!("No HELLO from {}, treating it as a legacy client", self.address);
+                    self.protocol = Some(Protocol::legacy());
+                }
+
+                match self.codec.decode(&frame, self.config.max_message_size, self.compression().is_some()) {
+                    Ok(msg) => self.receive_msg(msg).await,
+                    Err(e) => self.decode_failed(e.to_string()).await,
+                }
+            },
+            TkMessage::Close(close_frame) => {
+                info!("Client closed the connection: {:?}", close_frame);
+                self.closed = true;
+                Ok(())
+            },
+            // Pongs only matter to the heartbeat, and tungstenite answers pings on its own.
+            TkMessage::Ping(_) | TkMessage::Pong(_) | TkMessage::Frame(_) => Ok(()),
+        }
+    }
+
+    // The stream can't be read anymore after an error, only a close frame may still go out.
+    pub(crate) async fn receive_error(&mut self, e: TkError) -> Result<(), StdError> {
+        error!("Failed to read from {}: {e}", self.address);
+
+        match e {
+            TkError::Capacity(_) => self.close(CloseCode::Size, "Message too big!".to_string()).await,
+            TkError::Protocol(_) | TkError::Utf8 => self.close(CloseCode::Protocol, "Protocol error!".to_string()).await,
+            _ => {
+                self.closed = true;
+                Ok(())
+            },
+        }
+    }
+}
+// Private
+impl Coms {
+    async fn receive_msg(&mut self, msg: ServerMessage) -> Result<(), StdError> {
+        debug!("Received: {:?}", Redacted(&msg));
         self.manager.received(msg);
         let msgs = self.manager.received_waiting();
         self.handle_msg(msgs).await?;
 
         Ok(())
     }
-}
-// Private
-impl Coms {
+
     async fn handle_msg(&mut self, msgs: Vec<ServerMessage>) -> Result<(), StdError> {
         for msg in msgs {
-            let response_msg = match msg.content {
-                ServerMessageContent::SESSION(session) => Some(self.handle_session(msg.uuid, session).await),
-                ServerMessageContent::NOTIFICATION(notification) => Some(self.handle_notification(msg.uuid, notification).await?),
-                ServerMessageContent::MODIFICATION(modification) => Some(self.handle_modification(msg.uuid, modification).await?),
-                ServerMessageContent::QUERY(query) => Some(self.handle_query(msg.uuid, query).await),
-                _ => None,
-            };
-            
-            self.send_msg(response_msg).await?;
+            let kind = MessageKind::of(&msg.content).map_or("response", |kind| kind.name());
+            let span = info_span!("message", uuid = %msg.uuid, kind);
+
+            self.handle_one_msg(msg).instrument(span).await?;
+            if self.closed {
+                break;
+            }
         }
         
         Ok(())
     }
+
+    async fn handle_one_msg(&mut self, msg: ServerMessage) -> Result<(), StdError> {
+        if let Err(retry_after) = self.check_rate_limit(&msg.content) {
+            self.rate_limit_violations += 1;
+            if self.rate_limit_violations > self.config.max_rate_limit_violations {
+                return self.close(CloseCode::Policy, "Too many requests!".to_string()).await;
+            }
+
+            return self.send_msg(Some(create_response!(Response::Err, msg.uuid, ServerError::RATE_LIMITED(retry_after)))).await;
+        }
+        self.rate_limit_violations = 0;
+        if let Some(kind) = MessageKind::of(&msg.content) {
+            self.metrics.message_handled(kind.name());
+        }
+
+        let response_msg = match msg.content {
+            ServerMessageContent::SESSION(session) => Some(self.handle_session(msg.uuid, session).await),
+            ServerMessageContent::NOTIFICATION(notification) => Some(self.handle_notification(msg.uuid, notification).await),
+            ServerMessageContent::MODIFICATION(modification) => Some(self.handle_modification(msg.uuid, modification).await),
+            ServerMessageContent::QUERY(query) => Some(self.handle_query(msg.uuid, query).await),
+            _ => None,
+        }
+        .map(|response| response.unwrap_or_else(|e| create_response!(Response::Err, msg.uuid, e)));
+        
+        self.send_msg(response_msg).await
+    }
     
     async fn send_msg(&mut self, msg: Option<ServerMessage>) -> Result<(), StdError> {
         let msg = msg.ok_or("Message received is a Response!")?;
-        let bin_msg = TkMessage::Binary(serialize(&msg)?);
-        self.write.send(bin_msg).await?;
-
-        let current_user = self.mongo_db.get_full_user(self.user_uuid).await?;
-        warn!("Sent to: {}", current_user.tag());
-        warn!("Sent: {:#?}", msg);
-
+        let frame = self.codec.encode(&msg, self.compression())?;
+        self.write_frame(frame).await?;
+        debug!("Sent: {:?}", Redacted(&msg));
 
         self.manager.sent(msg);
 
         Ok(())
     }
     
-    async fn handle_session(&mut self, msg_uuid: UUID, session: Session) -> ServerMessage {
-        let (user_uuid, msg) = match match session {
-            Session::LOGIN(info) => self.mongo_db
-                .login(info).await
-                .map(|user| {
-                    (user.uuid(), create_response!(Response::OK_SESSION, msg_uuid, Session::TOKEN(user)))
-                }),
-            Session::SIGN_UP(info) => self.mongo_db
-                .sign_up(info).await
-                .map(|user| {
-                    (user.uuid(), create_response!(Response::OK_SESSION, msg_uuid, Session::TOKEN(user)))
-                }),
-            Session::TOKEN(_) => todo!(),
-        } {
-            Ok(content) => content,
-            Err(e) => return create_response!(Response::Err, msg_uuid, e.to_string()),
+    async fn handle_session(&mut self, msg_uuid: UUID, session: Session) -> Result<ServerMessage, ServerError> {
+        let user = match session {
+            Session::LOGIN(info) => login_guard::login(&self.login_guard, &self.mongo_db, info, self.address.ip()).await?,
+            Session::SIGN_UP(info) => login_guard::sign_up(&self.login_guard, &self.mongo_db, info, self.address.ip()).await?,
+            // Resuming with the user received at login, what is sent back is the stored user.
+            Session::TOKEN(user) => {
+                self.mongo_db.check_moderation(user.uuid()).await?;
+                self.mongo_db.get_full_user(user.uuid()).await?
+            },
         };
         
-        self.user_uuid = user_uuid;
+        self.user_uuid = user.uuid();
+        self.span.record("user", field::display(self.user_uuid));
+        self.span.record("tag", field::display(user.tag()));
+        let blocked_users = match self.mongo_db.get_blocked_users(self.user_uuid).await {
+            Ok(blocked) => blocked.into_iter().collect(),
+            Err(e) => {
+                error!("In Coms::handle_session: {e}");
+                HashSet::default()
+            }
+        };
+
         let user_chats = if let Ok(chats) = self.mongo_db.get_user_chats(self.user_uuid).await {
             chats.iter().map(|c| c.uuid()).collect()
         } else { vec![] };
 
         if let Err(e) = self.notification_manager_sender.send((
             self.user_uuid,
-            NotificationManagerMessage::NOTIFY_USER(self.user_uuid, user_chats, self.notification_sender.clone())
+            NotificationManagerMessage::NOTIFY_USER(self.user_uuid, user_chats, blocked_users, UserConnection {
+                notification_sender: self.notification_sender.clone(),
+                disconnect_sender: self.disconnect_sender.clone(),
+            })
         )).await {
             error!("In Coms::handle_session: {e}");
         }
 
-        msg
+        Ok(create_response!(Response::OK_SESSION, msg_uuid, Session::TOKEN(user)))
     }
     
     async fn handle_query(
         &self,
         msg_uuid: UUID,
         query: Query,
-    ) -> ServerMessage {
-        if !self.is_user_valid() { 
-            return ServerMessage::new(msg_uuid, ServerMessageContent::RESPONSE(Response::Err("User is not logged in, Server can't respond to query!".to_string()))); 
-        }
+    ) -> Result<ServerMessage, ServerError> {
+        policy::logged_in(self.user_uuid)?;
 
-        match match query {
+        match query {
             Query::USERS_BY_TAG(tags) => {
                 let users = self.mongo_db.query_by_tag(tags).await;
                 Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_USER(users)))
             },
-            Query::USERS_CONTAINS_TAG(tag) => self.mongo_db
-                .query_contains_tag(tag).await
-                .map(|users| create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_USER(users))),
+            Query::USERS_CONTAINS_TAG(tag) => {
+                let users = self.search_users(tag).await?;
+                Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_USER(users)))
+            },
             Query::USERS_BY_UUID(uuids) => {
                 let users = self.mongo_db.query_by_uuid(uuids).await;
                 Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_USER(users)))
             },
             Query::FRIEND_REQUESTS => {
-                self.mongo_db.get_user_friend_requests(self.user_uuid).await
-                    .map(|notifications| create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_FRIEND_REQUESTS(notifications)))
+                let notifications = self.mongo_db.get_user_friend_requests(self.user_uuid).await?;
+                Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_FRIEND_REQUESTS(notifications)))
             }
             Query::USER_CHATS => {
-                self.mongo_db.get_user_chats(self.user_uuid).await
-                    .map_err(|e| e.into())
-                    .map(|chats| {
-                        if self.user_uuid.is_valid() {
-                            create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_CHATS(chats))
-                        }
-                        else {
-                            create_response!(Response::Err, msg_uuid, "User is invalid!".to_string())
-                        }                        
-                    })
+                let chats = self.mongo_db.get_user_chats(self.user_uuid).await?;
+                Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_CHATS(chats)))
             },
             Query::CHAT_MESSAGES(chat_uuid) => {
-                self.mongo_db.get_chat(chat_uuid).await
-                    .map(|chat| {
-                        if chat.users().contains(&self.user_uuid) && self.user_uuid.is_valid() {
-                            create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_CHAT_MESSAGES(chat.messages().to_vec()))
-                        }
-                        else {
-                            create_response!(Response::Err, msg_uuid, "User is invalid or is not a member of the chat!".to_string())
-                        }
-                })
+                let chat = self.mongo_db.get_chat_messages(chat_uuid.to_string(), self.user_uuid, None).await?;
+                policy::chat_member(self.user_uuid, chat.users())?;
+
+                Ok(create_response!(Response::OK_QUERY, msg_uuid, Query::RESULT_CHAT_MESSAGES(chat.messages().to_vec())))
             }
     
-            _ => Err(std::format!("Invalid Query! {:#?}", query).into()),
-        } {
-            Ok(msg) => msg,
-            Err(e) => create_response!(Response::Err, msg_uuid, e.to_string()),
+            _ => {
+                warn!("In Coms::handle_query: Invalid Query! {:?}", query);
+                Err(ServerError::UNSUPPORTED_REQUEST)
+            },
         }
     }
     
-    async fn handle_notification(&mut self, msg_uuid: UUID, notification: Notification) -> Result<ServerMessage, StdError> {
-        if !self.is_user_valid() { return Err("User is not logged in, Server can't respond to notifications!".into()); }
-
-        // Handling the database
-        match notification.notification_type.clone() {
-            NotificationType::NEW_CHAT(chat) => {
-                // This is going to be shit.
-                if self.user_uuid.is_valid() {
-                    let mut members_are_friends = true;
-
-                    let _ = self.mongo_db.get_full_user(self.user_uuid).await?
-                        .friends()
-                        .iter()
-                        .map_while(|f| {
-                            if !chat.users().contains(&f.uuid()) {
-                                members_are_friends = false;
-                                return None;
-                            }
-                            
-                            Some(())
-                        });
-
-                    if chat.users().contains(&self.user_uuid) && members_are_friends {
-                        if let Err(e) = self.mongo_db.new_chat(&chat).await {
-                            return Ok(ServerMessage::new(msg_uuid, ServerMessageContent::RESPONSE(Response::Err(e.to_string()))));
-                        }
-                    }
-                }
-            },
-            NotificationType::NEW_MESSAGE(chat_uuid, message) => {
-                // Creating the notifications for all.
-                let chat = self.mongo_db.get_chat(chat_uuid).await?;
-                if self.user_uuid.is_valid() && chat.users().contains(&self.user_uuid) {
-                    for u in chat.users() {
-                        if *u != self.user_uuid {
-                            self.mongo_db.insert_notification(*u, &Notification::new(NotificationType::MESSAGE(chat_uuid))).await?;
-                            self.mongo_db.insert_message(chat.uuid(), message.clone()).await?;
-                        }
-                    }
-                }
-            }
-            NotificationType::MESSAGE_READ(chat_uuid) => {
-                if self.user_uuid.is_valid() {
-                    for notification in self.mongo_db.get_user_notifications(self.user_uuid).await? {
-                        match notification.notification_type {
-                            NotificationType::MESSAGE(notification_chat_uuid) => if chat_uuid == notification_chat_uuid {
-                                if let Err(e) = self.mongo_db.remove_notification(notification.uuid()).await {
-                                    error!("In Coms::handle_notification: {e}");
-                                }
-                            },
-                            _ => (),
-                        }
-                    }
-                }
-                else { error!("In Coms::handle_notification: User is not in Chat or User is invalid!"); }
-            }
-            NotificationType::FRIEND_REQUEST(sender, receiver) => {
-                if self.user_uuid == sender {
-                    // Saving the notification in the database.
-                    self.mongo_db.insert_non_duplicant_notification(receiver, &notification).await?;
-                }
-            },
-            NotificationType::FRIEND_ACCEPTED(sender, receiver) => {
-                if self.user_uuid == sender {
-                    // Removing the notification in the database.
-                    // Add the friend for both users
-
-                    self.mongo_db.remove_notification(notification.uuid()).await?;
-                    self.mongo_db.insert_friend(self.user_uuid, receiver).await?;
-                    self.mongo_db.insert_friend(receiver, self.user_uuid).await?;
-                        
-                    self.re_send_user().await?;
-                }
+    async fn handle_notification(&mut self, msg_uuid: UUID, notification: Notification) -> Result<ServerMessage, ServerError> {
+        policy::logged_in(self.user_uuid)?;
 
-            },
-            _ => (),
-        };
+        apply_notification(&self.mongo_db, &self.config, self.user_uuid, self.address.ip(), &notification).await?;
+        if let NotificationType::FRIEND_ACCEPTED(_, _) = notification.notification_type {
+            self.re_send_user().await?;
+        }
 
         if let Err(e) = self.notification_manager_sender.send((
             self.user_uuid,
@@ -292,15 +347,26 @@ impl Coms {
         Ok(ServerMessage::new(msg_uuid, ServerMessageContent::RESPONSE(Response::OK)))
     }
 
-    async fn handle_modification(&mut self, msg_uuid: UUID, modification: Modification) -> Result<ServerMessage, StdError> {
+    async fn handle_modification(&mut self, msg_uuid: UUID, modification: Modification) -> Result<ServerMessage, ServerError> {
+        policy::logged_in(self.user_uuid)?;
+
         match modification {
-            Modification::REMOVE_FRIEND(friend_uuid) => if self.user_uuid.is_valid() {
+            Modification::REMOVE_FRIEND(friend_uuid) => {
+                let friends = self.mongo_db.get_full_user(self.user_uuid).await?
+                    .friends()
+                    .iter()
+                    .map(|f| f.uuid())
+                    .collect::<HashSet<_>>();
+                policy::remove_friend(self.user_uuid, &friends, friend_uuid)?;
+
                 self.mongo_db.remove_friend(self.user_uuid, friend_uuid).await?;
                 self.mongo_db.remove_friend(friend_uuid, self.user_uuid).await?;
+                self.audit(AuditAction::FRIEND_REMOVE, friend_uuid.to_string(), &Ok(())).await;
 
                 for chat in self.mongo_db.get_user_chats(self.user_uuid).await? {
                     if chat.users().contains(&friend_uuid) {
                         self.mongo_db.remove_chat(chat.uuid()).await?;
+                        self.audit(AuditAction::CHAT_DELETE, chat.uuid().to_string(), &Ok(())).await;
                     }
                 }
                 self.re_send_user().await?;
@@ -313,36 +379,229 @@ impl Coms {
                 }
             },
             Modification::USER_TAG(user_uuid, new_tag) => {
-                if self.user_uuid == user_uuid {
-                    self.mongo_db.change_user_tag(user_uuid, new_tag).await?;
-                    let user = self.mongo_db.get_full_user(user_uuid).await?;
-                    self.re_send_user().await?;
+                // Recorded even when denied, changing someone else's tag is worth knowing about.
+                let changed = match policy::acting_as(self.user_uuid, user_uuid) {
+                    Ok(()) => self.mongo_db.change_user_tag(user_uuid, new_tag).await.map_err(ServerError::from),
+                    Err(denial) => Err(denial.into()),
+                };
+                self.audit(AuditAction::TAG_CHANGE, user_uuid.to_string(), &changed).await;
+                changed?;
+
+                let user = self.mongo_db.get_full_user(user_uuid).await?;
+                self.re_send_user().await?;
 
-                    for friend in user.friends() {
-                        self.notification_manager_sender.send((self.user_uuid, NotificationManagerMessage::REFRESH_USER(friend.uuid()))).await?;
-                    }
+                for friend in user.friends() {
+                    self.notification_manager_sender.send((self.user_uuid, NotificationManagerMessage::REFRESH_USER(friend.uuid()))).await?;
                 }
             }
-            _ => todo!()
+            _ => return Err(ServerError::UNSUPPORTED_REQUEST),
         }
 
         Ok(ServerMessage::new(msg_uuid, ServerMessageContent::RESPONSE(Response::OK)))
     }
 
+    async fn audit(&self, action: AuditAction, target: String, result: &Result<(), ServerError>) {
+        audit::record(&self.mongo_db, AuditEvent::with_result(action, Some(self.user_uuid.to_string()), Some(target), Some(self.address.ip()), result)).await;
+    }
+
+    async fn search_users(&self, tag: String) -> Result<Vec<User>, ServerError> {
+        let tag = tag.trim().to_string();
+        if tag.chars().count() < self.config.user_search_min_length {
+            return Err(ServerError::QUERY_TOO_SHORT(self.config.user_search_min_length));
+        }
+
+        Ok(self.mongo_db.query_contains_tag(self.user_uuid, tag, 0, self.config.user_search_page_size).await?)
+    }
+
+    fn check_rate_limit(&self, content: &ServerMessageContent) -> Result<(), std::time::Duration> {
+        let Some(kind) = MessageKind::of(content) else { return Ok(()) };
+
+        match self.rate_limiter.lock() {
+            Ok(mut rate_limiter) => rate_limiter.check(self.user_uuid, self.address.ip(), kind),
+            Err(e) => {
+                error!("In Coms::check_rate_limit: {e}");
+                Ok(())
+            }
+        }
+    }
+
     async fn re_send_user(&mut self) -> Result<(), StdError> {
         let user = self.mongo_db.get_full_user(self.user_uuid).await?;
+        self.span.record("tag", field::display(user.tag()));
+
         self.send_msg(Some(ServerMessage::from(ServerMessageContent::SESSION(Session::TOKEN(user))))).await
     }
 
-    fn is_user_valid(&self) -> bool {
-        self.user_uuid.get_value() != 0
+    async fn receive_hello(&mut self, text: &str) -> Result<(), StdError> {
+        let hello = match Hello::parse(text) {
+            Ok(hello) => hello,
+            Err(e) => return self.decode_failed(e).await,
+        };
+
+        let protocol = match Protocol::negotiate(&hello) {
+            Ok(protocol) => protocol,
+            Err(min_version) => {
+                let reason = format!("Upgrade required! Protocol {} is no longer supported, the minimum is {min_version}", hello.version);
+                return self.close(CloseCode::from(UPGRADE_REQUIRED), reason).await;
+            },
+        };
+        info!("Negotiated protocol {} with {}", protocol.version(), self.address);
+
+        let reply = protocol.hello().to_text();
+        self.protocol = Some(protocol);
+        self.write.send(TkMessage::Text(reply)).await?;
+
+        Ok(())
+    }
+
+    // The threshold to compress from, once the client asked for it in its HELLO.
+    fn compression(&self) -> Option<usize> {
+        let negotiated = self.protocol.as_ref().is_some_and(|protocol| protocol.has_capability(protocol::ZSTD));
+        (negotiated && self.codec.is_binary()).then_some(self.config.compression_threshold)
+    }
+
+    async fn decode_failed(&mut self, reason: String) -> Result<(), StdError> {
+        self.decode_failures += 1;
+        warn!("Undecodable message from {} ({}/{}): {reason}", self.address, self.decode_failures, self.config.max_decode_failures);
+
+        if self.decode_failures >= self.config.max_decode_failures {
+            return self.close(CloseCode::Invalid, "Too many malformed messages!".to_string()).await;
+        }
+
+        Ok(())
     }
+
+    // Pings every heartbeat interval, a ping is missed when nothing arrived since it was sent.
+    async fn heartbeat(&mut self) -> Result<(), StdError> {
+        if !self.user_uuid.is_valid() && self.connected_at.elapsed() > self.config.login_deadline {
+            return self.close(CloseCode::Policy, "Login timed out!".to_string()).await;
+        }
+        if self.last_ping.elapsed() < self.config.heartbeat_interval {
+            return Ok(());
+        }
+
+        if self.last_activity < self.last_ping {
+            self.missed_heartbeats += 1;
+            if self.missed_heartbeats >= self.config.max_missed_heartbeats {
+                return self.close(CloseCode::Policy, "Heartbeat timed out!".to_string()).await;
+            }
+        } else {
+            self.missed_heartbeats = 0;
+        }
+
+        self.last_ping = Instant::now();
+        self.write_frame(TkMessage::Ping(Vec::new())).await
+    }
+
+    // A half-open peer stops reading and the write hangs once the TCP buffer is full, while holding the Coms lock.
+    // The connection is given up on then, there is no point in sending it a close frame.
+    async fn write_frame(&mut self, frame: TkMessage) -> Result<(), StdError> {
+        match tokio::time::timeout(self.config.heartbeat_interval, self.write.send(frame)).await {
+            Ok(sent) => Ok(sent?),
+            Err(_) => {
+                warn!("Closing connection: Write timed out!");
+                self.closed = true;
+                Err("Write timed out!".into())
+            },
+        }
+    }
+
+    async fn close(&mut self, code: CloseCode, mut reason: String) -> Result<(), StdError> {
+        warn!("Closing connection: {reason}");
+        self.closed = true;
+
+        // Close frame reasons are limited to 123 bytes.
+        while reason.len() > 123 {
+            reason.pop();
+        }
+        let close_frame = TkMessage::Close(Some(CloseFrame { code, reason: reason.into() }));
+        tokio::time::timeout(self.config.heartbeat_interval, self.write.send(close_frame)).await??;
+
+        Ok(())
+    }
+
 }
 
-fn deserialize(bytes: Vec<u8>) -> Result<ServerMessage, StdError> {
-    Ok(yapping_core::bincode::deserialize::<ServerMessage>(&bytes)?)
+// Checks and stores what a client notification does, before it is forwarded to the NotificationManager.
+// Shared by Coms and the HTTP API.
+pub(crate) async fn apply_notification(mongo_db: &MongoDB, config: &ServerConfig, user_uuid: UUID, address: IpAddr, notification: &Notification) -> Result<(), ServerError> {
+    match notification.notification_type.clone() {
+        NotificationType::NEW_CHAT(chat) => {
+            let friends = mongo_db.get_full_user(user_uuid).await?
+                .friends()
+                .iter()
+                .map(|f| f.uuid())
+                .collect::<HashSet<_>>();
+
+            // Rejecting before anything is stored or sent to the other members.
+            policy::create_chat(user_uuid, chat.users(), &friends, config.max_chat_members)?;
+            mongo_db.new_chat(&chat).await?;
+            audit::record(mongo_db, AuditEvent::with_result(AuditAction::CHAT_CREATE, Some(user_uuid.to_string()), Some(chat.uuid().to_string()), Some(address), &Ok(()))).await;
+        },
+        NotificationType::NEW_MESSAGE(chat_uuid, message) => {
+            // Creating the notifications for all.
+            let chat = mongo_db.get_chat(chat_uuid).await?;
+            policy::chat_member(user_uuid, chat.users())?;
+
+            for u in chat.users() {
+                if *u != user_uuid {
+                    mongo_db.insert_notification(*u, &Notification::new(NotificationType::MESSAGE(chat_uuid))).await?;
+                    mongo_db.insert_message(chat.uuid(), user_uuid, message.clone()).await?;
+                }
+            }
+        }
+        NotificationType::MESSAGE_READ(chat_uuid) => {
+            let chat = mongo_db.get_chat(chat_uuid).await?;
+            policy::chat_member(user_uuid, chat.users())?;
+
+            for notification in mongo_db.get_user_notifications(user_uuid).await? {
+                match notification.notification_type {
+                    NotificationType::MESSAGE(notification_chat_uuid) => if chat_uuid == notification_chat_uuid {
+                        if let Err(e) = mongo_db.remove_notification(notification.uuid()).await {
+                            error!("In coms::apply_notification: {e}");
+                        }
+                    },
+                    _ => (),
+                }
+            }
+        }
+        NotificationType::FRIEND_REQUEST(sender, receiver) => {
+            let blocked = mongo_db.is_blocked(receiver, sender).await?;
+            policy::send_friend_request(user_uuid, sender, receiver, blocked)?;
+
+            // Saving the notification in the database.
+            mongo_db.insert_non_duplicant_notification(receiver, notification).await?;
+        },
+        // The receiver of a request accepting it, sender is the one accepting.
+        NotificationType::FRIEND_ACCEPTED(sender, receiver) => {
+            policy::acting_as(user_uuid, sender)?;
+            // Removing the notification in the database.
+            let pending = mongo_db.remove_friend_request(receiver, sender).await?;
+            policy::answer_friend_request(user_uuid, pending)?;
+
+            // Add the friend for both users
+            mongo_db.insert_friend(user_uuid, receiver).await?;
+            mongo_db.insert_friend(receiver, user_uuid).await?;
+            audit::record(mongo_db, AuditEvent::with_result(AuditAction::FRIEND_ADD, Some(user_uuid.to_string()), Some(receiver.to_string()), Some(address), &Ok(()))).await;
+        },
+        _ => (),
+    };
+
+    Ok(())
 }
 
-fn serialize(msg: &ServerMessage) -> Result<Vec<u8>, StdError> {
-    Ok(yapping_core::bincode::serialize(msg)?)
-}
\ No newline at end of file
+// Whatever ends the connection task, the user must not stay online.
+impl Drop for Coms {
+    fn drop(&mut self) {
+        if self.shut_down {
+            return;
+        }
+
+        if let Err(e) = self.notification_manager_sender.try_send((
+            self.user_uuid,
+            NotificationManagerMessage::USER_OFFLINE(self.notification_sender.clone())
+        )) {
+            error!("In Coms::drop: {e}");
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
+use crate::rate_limiter::{MessageKind, RateLimit};
+
+// Server settings, read once at boot from YAPPING_* environment variables.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub(crate) friend_request_ttl: Duration,
+    pub(crate) friend_request_sweep_interval: Duration,
+    pub(crate) max_chat_members: usize,
+    // Bans and suspensions from yapping_admin disconnect the user within this long.
+    pub(crate) moderation_poll_interval: Duration,
+    pub(crate) rate_limits: HashMap<MessageKind, RateLimit>,
+    // Rate limited messages in a row before the connection is closed.
+    pub(crate) max_rate_limit_violations: u32,
+    pub(crate) login_backoff_base: Duration,
+    pub(crate) login_backoff_max: Duration,
+    // Failed logins for the same email or address before it is locked out.
+    pub(crate) login_lockout_after: u32,
+    pub(crate) login_lockout_duration: Duration,
+    pub(crate) user_search_min_length: usize,
+    pub(crate) user_search_page_size: u32,
+    // Stripped user profiles kept in memory, 0 disables the cache.
+    pub(crate) user_cache_capacity: usize,
+    pub(crate) user_cache_ttl: Duration,
+    pub(crate) heartbeat_interval: Duration,
+    // Pings in a row left unanswered before the connection is considered dead.
+    pub(crate) max_missed_heartbeats: u32,
+    // How long a connection may stay open without logging in.
+    pub(crate) login_deadline: Duration,
+    // Notifications waiting to be written to a client before it counts as too slow.
+    pub(crate) notification_queue_depth: usize,
+    pub(crate) max_connections: usize,
+    pub(crate) max_connections_per_address: usize,
+    // In bytes, for frames and whole messages received from clients.
+    pub(crate) max_frame_size: usize,
+    pub(crate) max_message_size: usize,
+    // Undecodable messages tolerated before the connection is closed.
+    pub(crate) max_decode_failures: u32,
+    // Binary frames from this many bytes on are compressed, for clients that negotiated zstd.
+    pub(crate) compression_threshold: usize,
+    pub(crate) http_address: String,
+    // Serves /metrics and /health/*, apart from the API and local only by default.
+    pub(crate) metrics_address: String,
+    pub(crate) session_ttl: Duration,
+    // Largest page the HTTP API hands out, whatever the client asks for.
+    pub(crate) http_max_page_size: u32,
+    // A tracing filter directive, like "info" or "server=debug,mongodb=warn".
+    pub(crate) log_filter: String,
+    // One JSON object per line instead of human readable lines.
+    pub(crate) log_json: bool,
+}
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        Self {
+            friend_request_ttl: Duration::from_secs(env_or("YAPPING_FRIEND_REQUEST_TTL_HOURS", 24 * 7) * 60 * 60),
+            friend_request_sweep_interval: Duration::from_secs(env_or("YAPPING_FRIEND_REQUEST_SWEEP_SECS", 10 * 60)),
+            max_chat_members: env_or("YAPPING_MAX_CHAT_MEMBERS", 32),
+            moderation_poll_interval: Duration::from_millis(env_or("YAPPING_MODERATION_POLL_MS", 1000)),
+            rate_limits: HashMap::from([
+                (MessageKind::SESSION, env_or("YAPPING_RATE_LIMIT_SESSION", RateLimit { burst: 5, per_minute: 10 })),
+                (MessageKind::QUERY, env_or("YAPPING_RATE_LIMIT_QUERY", RateLimit { burst: 20, per_minute: 120 })),
+                (MessageKind::NOTIFICATION, env_or("YAPPING_RATE_LIMIT_NOTIFICATION", RateLimit { burst: 30, per_minute: 240 })),
+                (MessageKind::MODIFICATION, env_or("YAPPING_RATE_LIMIT_MODIFICATION", RateLimit { burst: 10, per_minute: 30 })),
+            ]),
+            max_rate_limit_violations: env_or("YAPPING_MAX_RATE_LIMIT_VIOLATIONS", 20),
+            login_backoff_base: Duration::from_millis(env_or("YAPPING_LOGIN_BACKOFF_BASE_MS", 500)),
+            login_backoff_max: Duration::from_secs(env_or("YAPPING_LOGIN_BACKOFF_MAX_SECS", 30)),
+            login_lockout_after: env_or("YAPPING_LOGIN_LOCKOUT_AFTER", 10),
+            login_lockout_duration: Duration::from_secs(env_or("YAPPING_LOGIN_LOCKOUT_MINUTES", 15) * 60),
+            user_search_min_length: env_or("YAPPING_USER_SEARCH_MIN_LENGTH", 3),
+            user_search_page_size: env_or("YAPPING_USER_SEARCH_PAGE_SIZE", 20),
+            user_cache_capacity: env_or("YAPPING_USER_CACHE_CAPACITY", 10_000),
+            user_cache_ttl: Duration::from_secs(env_or("YAPPING_USER_CACHE_TTL_SECS", 60)),
+            heartbeat_interval: Duration::from_secs(env_or("YAPPING_HEARTBEAT_INTERVAL_SECS", 30)),
+            max_missed_heartbeats: env_or("YAPPING_MAX_MISSED_HEARTBEATS", 3),
+            login_deadline: Duration::from_secs(env_or("YAPPING_LOGIN_DEADLINE_SECS", 60)),
+            notification_queue_depth: env_or("YAPPING_NOTIFICATION_QUEUE_DEPTH", 100),
+            max_connections: env_or("YAPPING_MAX_CONNECTIONS", 10_000),
+            max_connections_per_address: env_or("YAPPING_MAX_CONNECTIONS_PER_ADDRESS", 16),
+            max_frame_size: env_or("YAPPING_MAX_FRAME_SIZE", 256 * 1024),
+            max_message_size: env_or("YAPPING_MAX_MESSAGE_SIZE", 1024 * 1024),
+            max_decode_failures: env_or("YAPPING_MAX_DECODE_FAILURES", 5),
+            compression_threshold: env_or("YAPPING_COMPRESSION_THRESHOLD", 4 * 1024),
+            http_address: env_or("YAPPING_HTTP_ADDRESS", "0.0.0.0:8081".to_string()),
+            metrics_address: env_or("YAPPING_METRICS_ADDRESS", "127.0.0.1:9090".to_string()),
+            session_ttl: Duration::from_secs(env_or("YAPPING_SESSION_TTL_DAYS", 30) * 24 * 60 * 60),
+            http_max_page_size: env_or("YAPPING_HTTP_MAX_PAGE_SIZE", 100),
+            log_filter: env_or("YAPPING_LOG", if cfg!(debug_assertions) { "debug" } else { "info" }.to_string()),
+            log_json: env_or("YAPPING_LOG_JSON", false),
+        }
+    }
+}
+
+// Read before logging is set up, so problems go straight to stderr.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value for {name}: {value}, using the default");
+            default
+        }),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,595 @@
+use std::{collections::HashSet, convert::Infallible, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Path, Query, State},
+    http::{header::{AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER}, request::Parts, HeaderValue, Method, StatusCode},
+    response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response},
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use futures::{stream, Stream};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tracing::{error, info};
+use yapping_core::{chat::Chat, client_server_coms::{Notification, NotificationType}, l3gion_rust::{StdError, UUID}, message::Message, user::{User, UserCreationInfo}};
+
+use crate::{audit::{self, AuditAction, AuditEvent}, coms, config::ServerConfig, login_guard::{self, LoginGuard}, metrics::Metrics, mongo_db::{MongoDB, Paging}, notification_manager::{NotificationManagerMessage, UserConnection}, policy, rate_limiter::{MessageKind, RateLimiter}, server_error::{ErrorCategory, ServerError}};
+
+// The same operations as Coms::handle_query and Coms::handle_modification, over HTTP with JSON bodies.
+// Clients log in once through POST /api/v1/sessions and send the token as "Authorization: Bearer <token>".
+// Where WebSockets are blocked, GET /api/v1/events streams notifications and POST /api/v1/notifications sends them.
+// Blocking and declining or canceling friend requests have no WebSocket message, they are only offered here.
+// GET /health/live, /health/ready and /metrics are served without authentication on their own address, see serve_metrics.
+
+#[derive(Clone)]
+pub(crate) struct ApiState {
+    pub(crate) config: ServerConfig,
+    pub(crate) rate_limiter: Arc<Mutex<RateLimiter>>,
+    pub(crate) login_guard: Arc<Mutex<LoginGuard>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) mongo_db: MongoDB,
+    pub(crate) notification_manager_sender: Sender<(UUID, NotificationManagerMessage)>,
+}
+
+pub(crate) async fn serve(state: ApiState) -> Result<(), StdError> {
+    state.mongo_db.create_session_index().await?;
+
+    let listener = tokio::net::TcpListener::bind(&state.config.http_address).await?;
+    info!("HTTP API is now running on {}!", state.config.http_address);
+
+    axum::serve(listener, router(state).into_make_service_with_connect_info::<SocketAddr>()).await?;
+
+    Ok(())
+}
+
+// For the orchestrator and Prometheus, kept off the public API listener.
+pub(crate) async fn serve_metrics(state: ApiState) -> Result<(), StdError> {
+    let listener = tokio::net::TcpListener::bind(&state.config.metrics_address).await?;
+    info!("Metrics and health checks are now served on {}!", state.config.metrics_address);
+
+    axum::serve(listener, metrics_router(state)).await?;
+
+    Ok(())
+}
+
+fn metrics_router(state: ApiState) -> Router {
+    Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/api/v1/sessions", post(create_session).delete(delete_session))
+        .route("/api/v1/me", get(get_me).patch(update_me))
+        .route("/api/v1/users", get(get_users))
+        .route("/api/v1/chats", get(get_chats))
+        .route("/api/v1/chats/:chat_id/messages", get(get_chat_messages))
+        .route("/api/v1/friend-requests", get(get_friend_requests))
+        .route("/api/v1/friend-requests/sent", get(get_sent_friend_requests))
+        .route("/api/v1/friend-requests/sent/:user_id", delete(cancel_friend_request))
+        .route("/api/v1/friend-requests/:user_id", delete(decline_friend_request))
+        .route("/api/v1/friends/:user_id", delete(remove_friend))
+        .route("/api/v1/blocks/:user_id", put(block_user).delete(unblock_user))
+        .route("/api/v1/events", get(get_events))
+        .route("/api/v1/notifications", post(send_notification))
+        .with_state(state)
+}
+
+// The logged in user, behind every /api/v1 route but POST /api/v1/sessions.
+// Rate limited like the WebSocket messages the route stands for.
+struct AuthUser {
+    uuid: UUID,
+    token: String,
+    address: IpAddr,
+}
+impl AuthUser {
+    async fn audit(&self, mongo_db: &MongoDB, action: AuditAction, target: String, result: &Result<(), ServerError>) {
+        audit::record(mongo_db, AuditEvent::with_result(action, Some(self.uuid.to_string()), Some(target), Some(self.address), result)).await;
+    }
+}
+#[async_trait]
+impl FromRequestParts<ApiState> for AuthUser {
+    type Rejection = ServerError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &ApiState) -> Result<Self, Self::Rejection> {
+        let token = parts.headers.get(AUTHORIZATION)
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or(ServerError::INVALID_SESSION)?
+            .to_string();
+
+        let user = state.mongo_db.get_session_user(&token).await?.ok_or(ServerError::INVALID_SESSION)?;
+        state.mongo_db.check_moderation(user.uuid()).await?;
+
+        let kind = match (&parts.method, parts.uri.path()) {
+            (&Method::GET, _) => MessageKind::QUERY,
+            (_, "/api/v1/notifications") => MessageKind::NOTIFICATION,
+            _ => MessageKind::MODIFICATION,
+        };
+        let address = client_address(parts);
+        state.check_rate_limit(user.uuid(), address, kind)?;
+
+        Ok(Self { uuid: user.uuid(), token, address })
+    }
+}
+
+// Answers as long as the HTTP API task runs.
+async fn live() -> StatusCode {
+    StatusCode::OK
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    mongo_db: bool,
+    notification_manager: bool,
+}
+
+// Ready once MongoDB answers and the NotificationManager task still receives, its receiver is dropped with the task.
+async fn ready(State(state): State<ApiState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = Readiness {
+        mongo_db: match state.mongo_db.ping().await {
+            Ok(()) => true,
+            Err(e) => {
+                error!("In http_api::ready: {e}");
+                false
+            },
+        },
+        notification_manager: !state.notification_manager_sender.is_closed(),
+    };
+
+    let status = if readiness.mongo_db && readiness.notification_manager { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness))
+}
+
+async fn metrics(State(state): State<ApiState>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render())
+}
+
+#[derive(Deserialize)]
+struct PageQuery {
+    #[serde(default)]
+    page: u32,
+    page_size: Option<u32>,
+}
+impl PageQuery {
+    fn page_size(&self, max_page_size: u32) -> u32 {
+        let max_page_size = max_page_size.max(1);
+        self.page_size.unwrap_or(max_page_size).clamp(1, max_page_size)
+    }
+
+    // One item more than the page, telling whether another page follows.
+    fn paging(&self, max_page_size: u32) -> Paging {
+        let page_size = self.page_size(max_page_size);
+
+        Paging {
+            skip: u64::from(self.page) * u64::from(page_size),
+            limit: i64::from(page_size) + 1,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Page<T> {
+    items: Vec<T>,
+    page: u32,
+    page_size: u32,
+    has_more: bool,
+}
+impl<T> Page<T> {
+    // From items loaded with PageQuery::paging.
+    fn new(mut items: Vec<T>, query: &PageQuery, max_page_size: u32) -> Self {
+        let page_size = query.page_size(max_page_size);
+        let has_more = items.len() > page_size as usize;
+        items.truncate(page_size as usize);
+
+        Self {
+            items,
+            page: query.page,
+            page_size,
+            has_more,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionBody {
+    token: String,
+    expires_at: String,
+    user: User,
+}
+
+async fn create_session(
+    State(state): State<ApiState>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    Json(info): Json<UserCreationInfo>,
+) -> Result<Json<SessionBody>, ServerError> {
+    state.check_rate_limit(UUID::default(), address.ip(), MessageKind::SESSION)?;
+
+    let user = login_guard::login(&state.login_guard, &state.mongo_db, info, address.ip()).await?;
+
+    let token = new_token();
+    let expires_at = state.mongo_db.create_session(user.uuid(), &token, state.config.session_ttl).await?;
+
+    Ok(Json(SessionBody {
+        token,
+        expires_at: expires_at.try_to_rfc3339_string().unwrap_or_default(),
+        user,
+    }))
+}
+
+async fn delete_session(State(state): State<ApiState>, auth: AuthUser) -> Result<StatusCode, ServerError> {
+    state.mongo_db.remove_session(&auth.token).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_me(State(state): State<ApiState>, auth: AuthUser) -> Result<Json<User>, ServerError> {
+    Ok(Json(state.mongo_db.get_full_user(auth.uuid).await?))
+}
+
+#[derive(Deserialize)]
+struct ProfileBody {
+    tag: Option<String>,
+}
+
+async fn update_me(State(state): State<ApiState>, auth: AuthUser, Json(profile): Json<ProfileBody>) -> Result<Json<User>, ServerError> {
+    if let Some(tag) = profile.tag {
+        if tag.trim().is_empty() {
+            return Err(ServerError::MISSING_FIELDS);
+        }
+        let changed = state.mongo_db.change_user_tag(auth.uuid, tag).await.map_err(ServerError::from);
+        auth.audit(&state.mongo_db, AuditAction::TAG_CHANGE, auth.uuid.to_string(), &changed).await;
+        changed?;
+    }
+
+    let user = state.mongo_db.get_full_user(auth.uuid).await?;
+    state.refresh(auth.uuid).await;
+    for friend in user.friends() {
+        state.refresh(friend.uuid()).await;
+    }
+
+    Ok(Json(user))
+}
+
+// One of "tag" (contains, paginated), "tags" or "ids", the last two comma separated.
+#[derive(Deserialize)]
+struct UsersQuery {
+    tag: Option<String>,
+    tags: Option<String>,
+    ids: Option<String>,
+    #[serde(default)]
+    page: u32,
+}
+
+async fn get_users(State(state): State<ApiState>, auth: AuthUser, Query(query): Query<UsersQuery>) -> Result<Json<Page<User>>, ServerError> {
+    let page_size = state.config.user_search_page_size;
+
+    let (list, by_tag) = match (query.tag, query.tags, query.ids) {
+        (Some(tag), _, _) => {
+            let tag = tag.trim().to_string();
+            if tag.chars().count() < state.config.user_search_min_length {
+                return Err(ServerError::QUERY_TOO_SHORT(state.config.user_search_min_length));
+            }
+
+            let users = state.mongo_db.query_contains_tag(auth.uuid, tag, query.page, page_size).await?;
+            let has_more = users.len() == page_size as usize;
+            return Ok(Json(Page { items: users, page: query.page, page_size, has_more }));
+        },
+        (None, Some(tags), _) => (tags, true),
+        (None, None, Some(ids)) => (ids, false),
+        (None, None, None) => return Err(ServerError::MISSING_FIELDS),
+    };
+
+    // Only the requested tags or ids on this page are looked up.
+    let page = PageQuery { page: query.page, page_size: None };
+    let paging = page.paging(state.config.http_max_page_size);
+    let requested = split_list(&list)
+        .into_iter()
+        .skip(paging.skip as usize)
+        .take(paging.limit as usize)
+        .collect::<Vec<_>>();
+    let requested = Page::new(requested, &page, state.config.http_max_page_size);
+
+    let users = if by_tag {
+        state.mongo_db.query_by_tag(requested.items).await
+    } else {
+        state.mongo_db.query_by_ids(requested.items).await
+    };
+
+    Ok(Json(Page { items: users, page: requested.page, page_size: requested.page_size, has_more: requested.has_more }))
+}
+
+// Without their messages, those are paged through get_chat_messages.
+async fn get_chats(State(state): State<ApiState>, auth: AuthUser, Query(page): Query<PageQuery>) -> Result<Json<Page<Chat>>, ServerError> {
+    let chats = state.mongo_db.get_user_chats_page(auth.uuid, page.paging(state.config.http_max_page_size)).await?;
+
+    Ok(Json(Page::new(chats, &page, state.config.http_max_page_size)))
+}
+
+// Newest first.
+async fn get_chat_messages(
+    State(state): State<ApiState>,
+    auth: AuthUser,
+    Path(chat_id): Path<String>,
+    Query(page): Query<PageQuery>,
+) -> Result<Json<Page<Message>>, ServerError> {
+    // Other users' chats look like they don't exist.
+    let paging = page.paging(state.config.http_max_page_size);
+    let chat = state.mongo_db.get_chat_messages(chat_id, auth.uuid, Some(paging)).await?;
+    policy::chat_member(auth.uuid, chat.users()).map_err(|_| ServerError::CHAT_NOT_FOUND)?;
+
+    Ok(Json(Page::new(chat.messages().to_vec(), &page, state.config.http_max_page_size)))
+}
+
+async fn get_friend_requests(State(state): State<ApiState>, auth: AuthUser, Query(page): Query<PageQuery>) -> Result<Json<Page<Notification>>, ServerError> {
+    let requests = state.mongo_db.get_user_friend_requests_page(auth.uuid, page.paging(state.config.http_max_page_size)).await?;
+
+    Ok(Json(Page::new(requests, &page, state.config.http_max_page_size)))
+}
+
+async fn get_sent_friend_requests(State(state): State<ApiState>, auth: AuthUser, Query(page): Query<PageQuery>) -> Result<Json<Page<Notification>>, ServerError> {
+    let requests = state.mongo_db.get_user_sent_friend_requests(auth.uuid, page.paging(state.config.http_max_page_size)).await?;
+
+    Ok(Json(Page::new(requests, &page, state.config.http_max_page_size)))
+}
+
+// Requests are only answered over HTTP, the other user is resent to its clients so they drop the request.
+async fn decline_friend_request(State(state): State<ApiState>, auth: AuthUser, Path(user_id): Path<String>) -> Result<StatusCode, ServerError> {
+    let sender_uuid = state.resolve_user(user_id).await?;
+    let pending = state.mongo_db.remove_friend_request(sender_uuid, auth.uuid).await?;
+    policy::answer_friend_request(auth.uuid, pending)?;
+
+    state.refresh(auth.uuid).await;
+    state.refresh(sender_uuid).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn cancel_friend_request(State(state): State<ApiState>, auth: AuthUser, Path(user_id): Path<String>) -> Result<StatusCode, ServerError> {
+    let receiver_uuid = state.resolve_user(user_id).await?;
+    let pending = state.mongo_db.remove_friend_request(auth.uuid, receiver_uuid).await?;
+    policy::answer_friend_request(auth.uuid, pending)?;
+
+    state.refresh(auth.uuid).await;
+    state.refresh(receiver_uuid).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_friend(State(state): State<ApiState>, auth: AuthUser, Path(user_id): Path<String>) -> Result<StatusCode, ServerError> {
+    let friend_uuid = state.resolve_user(user_id).await?;
+    let friends = state.mongo_db.get_full_user(auth.uuid).await?
+        .friends()
+        .iter()
+        .map(|f| f.uuid())
+        .collect::<HashSet<_>>();
+    policy::remove_friend(auth.uuid, &friends, friend_uuid)?;
+
+    state.mongo_db.remove_friend(auth.uuid, friend_uuid).await?;
+    state.mongo_db.remove_friend(friend_uuid, auth.uuid).await?;
+    auth.audit(&state.mongo_db, AuditAction::FRIEND_REMOVE, friend_uuid.to_string(), &Ok(())).await;
+
+    for chat in state.mongo_db.get_user_chats(auth.uuid).await? {
+        if chat.users().contains(&friend_uuid) {
+            state.mongo_db.remove_chat(chat.uuid()).await?;
+            auth.audit(&state.mongo_db, AuditAction::CHAT_DELETE, chat.uuid().to_string(), &Ok(())).await;
+        }
+    }
+
+    state.refresh(auth.uuid).await;
+    state.refresh(friend_uuid).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn block_user(State(state): State<ApiState>, auth: AuthUser, Path(user_id): Path<String>) -> Result<StatusCode, ServerError> {
+    let blocked_uuid = state.resolve_user(user_id).await?;
+    policy::block_user(auth.uuid, blocked_uuid)?;
+    state.mongo_db.block_user(auth.uuid, blocked_uuid).await?;
+
+    // Dropping pending friend requests from the blocked user.
+    for request in state.mongo_db.get_user_friend_requests(auth.uuid).await? {
+        if let NotificationType::FRIEND_REQUEST(sender, _) = request.notification_type {
+            if sender == blocked_uuid {
+                state.mongo_db.remove_notification(request.uuid()).await?;
+            }
+        }
+    }
+    state.refresh_blocked_users(auth.uuid).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn unblock_user(State(state): State<ApiState>, auth: AuthUser, Path(user_id): Path<String>) -> Result<StatusCode, ServerError> {
+    let blocked_uuid = state.resolve_user(user_id).await?;
+    state.mongo_db.unblock_user(auth.uuid, blocked_uuid).await?;
+    state.refresh_blocked_users(auth.uuid).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn send_notification(State(state): State<ApiState>, auth: AuthUser, Json(notification): Json<Notification>) -> Result<StatusCode, ServerError> {
+    coms::apply_notification(&state.mongo_db, &state.config, auth.uuid, auth.address, &notification).await?;
+    if let NotificationType::FRIEND_ACCEPTED(_, _) = notification.notification_type {
+        state.refresh(auth.uuid).await;
+    }
+
+    state.notification_manager_sender.send((auth.uuid, NotificationManagerMessage::CLIENT_MESSAGE(notification))).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// Registered in the NotificationManager like a Coms, next to the user's other connections.
+// Sends "notification" events, "user" events where a Coms would resend the user, and a last "disconnect" event.
+async fn get_events(State(state): State<ApiState>, auth: AuthUser) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ServerError> {
+    let (notification_sender, notification_receiver) = tokio::sync::mpsc::channel(state.config.notification_queue_depth.max(1));
+    let (disconnect_sender, disconnect_receiver) = tokio::sync::mpsc::channel(1);
+
+    let user_chats = state.mongo_db.get_user_chats(auth.uuid).await?.iter().map(|c| c.uuid()).collect();
+    let blocked_users = state.mongo_db.get_blocked_users(auth.uuid).await?.into_iter().collect();
+
+    state.notification_manager_sender.send((
+        auth.uuid,
+        NotificationManagerMessage::NOTIFY_USER(auth.uuid, user_chats, blocked_users, UserConnection { notification_sender: notification_sender.clone(), disconnect_sender }),
+    )).await?;
+
+    let events = EventStream {
+        user_uuid: auth.uuid,
+        notification_sender,
+        notification_receiver,
+        disconnect_receiver,
+        closed: false,
+        mongo_db: state.mongo_db.clone(),
+        notification_manager_sender: state.notification_manager_sender.clone(),
+    };
+
+    Ok(Sse::new(stream::unfold(events, |mut events| async move {
+        let event = events.next_event().await?;
+        Some((Ok(event), events))
+    }))
+    .keep_alive(KeepAlive::default()))
+}
+
+struct EventStream {
+    user_uuid: UUID,
+    // Tells the NotificationManager which of the user's connections went away.
+    notification_sender: Sender<Notification>,
+    notification_receiver: Receiver<Notification>,
+    disconnect_receiver: Receiver<String>,
+    closed: bool,
+
+    mongo_db: MongoDB,
+    notification_manager_sender: Sender<(UUID, NotificationManagerMessage)>,
+}
+impl EventStream {
+    // None ends the stream.
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if self.closed {
+                return None;
+            }
+
+            let notification = tokio::select! {
+                notification = self.notification_receiver.recv() => notification?,
+                reason = self.disconnect_receiver.recv() => {
+                    self.closed = true;
+                    return Some(Event::default().event("disconnect").data(reason.unwrap_or_default()));
+                },
+            };
+
+            let event = match &notification.notification_type {
+                NotificationType::RESEND_USER(_)
+                | NotificationType::FRIEND_ACCEPTED(_, _) => match self.mongo_db.get_full_user(self.user_uuid).await {
+                    Ok(user) => Event::default().event("user").json_data(&user),
+                    Err(e) => {
+                        error!("In EventStream::next_event: {e}");
+                        continue;
+                    },
+                },
+                _ => Event::default().event("notification").json_data(&notification),
+            };
+
+            match event {
+                Ok(event) => return Some(event),
+                Err(e) => error!("In EventStream::next_event: {e}"),
+            }
+        }
+    }
+}
+// The client going away drops the stream, the user must not stay online.
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        if let Err(e) = self.notification_manager_sender.try_send((self.user_uuid, NotificationManagerMessage::USER_OFFLINE(self.notification_sender.clone()))) {
+            error!("In EventStream::drop: {e}");
+        }
+    }
+}
+
+// Private
+impl ApiState {
+    fn check_rate_limit(&self, user: UUID, address: IpAddr, kind: MessageKind) -> Result<(), ServerError> {
+        let allowed = self.rate_limiter.lock()
+            .map_err(|e| ServerError::INTERNAL(e.to_string()))?
+            .check(user, address, kind);
+
+        allowed.map_err(ServerError::RATE_LIMITED)
+    }
+
+    // Ids in paths are strings as stored in the database.
+    async fn resolve_user(&self, user_id: String) -> Result<UUID, ServerError> {
+        self.mongo_db.query_by_ids(vec![user_id]).await
+            .first()
+            .map(|user| user.uuid())
+            .ok_or(ServerError::USER_NOT_FOUND)
+    }
+
+    // Live connections of the user resend it to their client.
+    async fn refresh(&self, user: UUID) {
+        if let Err(e) = self.notification_manager_sender.send((user, NotificationManagerMessage::REFRESH_USER(user))).await {
+            error!("In ApiState::refresh: {e}");
+        }
+    }
+
+    // The NotificationManager filters chat messages for the user's live connections with it.
+    async fn refresh_blocked_users(&self, user: UUID) -> Result<(), ServerError> {
+        let blocked_users = self.mongo_db.get_blocked_users(user).await?.into_iter().collect();
+        if let Err(e) = self.notification_manager_sender.send((user, NotificationManagerMessage::BLOCKED_USERS(user, blocked_users))).await {
+            error!("In ApiState::refresh_blocked_users: {e}");
+        }
+
+        Ok(())
+    }
+}
+
+impl IntoResponse for ServerError {
+    fn into_response(self) -> Response {
+        self.log();
+
+        let status = match (&self, self.category()) {
+            (ServerError::DENIED(_) | ServerError::ACCOUNT_BANNED(_) | ServerError::ACCOUNT_SUSPENDED(_, _), _) => StatusCode::FORBIDDEN,
+            (_, ErrorCategory::AUTH) => StatusCode::UNAUTHORIZED,
+            (_, ErrorCategory::VALIDATION) => StatusCode::BAD_REQUEST,
+            (_, ErrorCategory::NOT_FOUND) => StatusCode::NOT_FOUND,
+            (_, ErrorCategory::CONFLICT) => StatusCode::CONFLICT,
+            (_, ErrorCategory::RATE_LIMITED) => StatusCode::TOO_MANY_REQUESTS,
+            (_, ErrorCategory::INTERNAL) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        let mut response = (status, Json(self.to_json())).into_response();
+
+        if let ServerError::RATE_LIMITED(retry_after) = self {
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+
+        response
+    }
+}
+
+fn client_address(parts: &Parts) -> IpAddr {
+    parts.extensions.get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip())
+        .unwrap_or(IpAddr::from([0, 0, 0, 0]))
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// 256 random bits, hex encoded.
+fn new_token() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 32]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
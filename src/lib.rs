@@ -0,0 +1,20 @@
+// Shared by the server and yapping_admin binaries, and the benches.
+pub mod audit;
+pub mod codec;
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod migrations;
+pub mod mongo_db;
+pub mod server_manager;
+pub mod user_cache;
+
+mod chat_manager;
+mod coms;
+mod http_api;
+mod login_guard;
+mod notification_manager;
+mod policy;
+mod protocol;
+mod rate_limiter;
+mod server_error;
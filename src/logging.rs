@@ -0,0 +1,48 @@
+use std::fmt;
+
+use tracing_subscriber::EnvFilter;
+use yapping_core::{client_server_coms::{ServerMessage, ServerMessageContent, Session}, user::UserCreationInfo};
+
+use crate::config::ServerConfig;
+
+pub fn init(config: &ServerConfig) {
+    let filter = EnvFilter::try_new(&config.log_filter).unwrap_or_else(|e| {
+        eprintln!("Invalid YAPPING_LOG {}: {e}, using info", config.log_filter);
+        EnvFilter::new("info")
+    });
+
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if config.log_json {
+        subscriber.json().flatten_event(true).with_current_span(true).with_span_list(true).init();
+    } else {
+        subscriber.init();
+    }
+}
+
+// Logs a ServerMessage without the passwords of Session::LOGIN and Session::SIGN_UP.
+pub(crate) struct Redacted<'a>(pub(crate) &'a ServerMessage);
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (variant, info) = match &self.0.content {
+            ServerMessageContent::SESSION(Session::LOGIN(info)) => ("LOGIN", info),
+            ServerMessageContent::SESSION(Session::SIGN_UP(info)) => ("SIGN_UP", info),
+            _ => return fmt::Debug::fmt(self.0, f),
+        };
+
+        f.debug_struct("ServerMessage")
+            .field("uuid", &self.0.uuid)
+            .field("content", &format_args!("SESSION({variant}({:?}))", RedactedInfo(info)))
+            .finish()
+    }
+}
+
+struct RedactedInfo<'a>(&'a UserCreationInfo);
+impl fmt::Debug for RedactedInfo<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UserCreationInfo")
+            .field("tag", &self.0.tag)
+            .field("email", &self.0.email)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
@@ -0,0 +1,263 @@
+use std::{collections::HashMap, hash::Hash, net::IpAddr, sync::Mutex, time::{Duration, Instant}};
+
+use tracing::warn;
+use yapping_core::user::{User, UserCreationInfo};
+
+use crate::{audit::{self, AuditAction, AuditEvent}, config::ServerConfig, mongo_db::MongoDB, server_error::ServerError};
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Lockout {
+    EMAIL(String),
+    ADDRESS(IpAddr),
+}
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+// Failed logins per email and per address, shared by every connection.
+// Each failure doubles the wait before the next attempt, and too many lock the email or address out.
+pub(crate) struct LoginGuard {
+    backoff_base: Duration,
+    backoff_max: Duration,
+    lockout_after: u32,
+    lockout_duration: Duration,
+
+    by_email: HashMap<String, Attempts>,
+    by_address: HashMap<IpAddr, Attempts>,
+}
+impl LoginGuard {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Self {
+            backoff_base: config.login_backoff_base,
+            backoff_max: config.login_backoff_max,
+            lockout_after: config.login_lockout_after,
+            lockout_duration: config.login_lockout_duration,
+            by_email: HashMap::default(),
+            by_address: HashMap::default(),
+        }
+    }
+
+    // Counts the attempt as failed before the credentials are even checked, under the same lock as the check.
+    // Otherwise concurrent guesses would all pass the check before any of them failed.
+    // Fails with how long to wait, or returns the lockouts this attempt starts if it does fail.
+    pub(crate) fn begin_attempt(&mut self, email: &str, address: IpAddr) -> Result<Vec<Lockout>, Duration> {
+        self.check(email, address)?;
+        Ok(self.record_failure(email, address))
+    }
+
+    // Takes back an attempt that failed for reasons unrelated to the credentials.
+    pub(crate) fn cancel_attempt(&mut self, email: &str, address: IpAddr) {
+        let lockout_after = self.lockout_after;
+        let cancel = |attempts: &mut Attempts| {
+            attempts.failures = attempts.failures.saturating_sub(1);
+            if attempts.failures < lockout_after {
+                attempts.locked_until = None;
+            }
+        };
+
+        if let Some(attempts) = self.by_email.get_mut(&email.to_lowercase()) {
+            cancel(attempts);
+        }
+        if let Some(attempts) = self.by_address.get_mut(&address) {
+            cancel(attempts);
+        }
+    }
+
+    // Fails with how long to wait before another attempt is allowed.
+    pub(crate) fn check(&self, email: &str, address: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+
+        let wait = [
+            self.by_email.get(&email.to_lowercase()),
+            self.by_address.get(&address),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|attempts| self.allowed_at(attempts))
+        .filter(|allowed_at| *allowed_at > now)
+        .map(|allowed_at| allowed_at - now)
+        .max();
+
+        match wait {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+
+    // Returns the lockouts this failure started.
+    pub(crate) fn record_failure(&mut self, email: &str, address: IpAddr) -> Vec<Lockout> {
+        self.prune();
+
+        let mut lockouts = Vec::new();
+        if record(&mut self.by_email, email.to_lowercase(), self.lockout_after, self.lockout_duration) {
+            lockouts.push(Lockout::EMAIL(email.to_lowercase()));
+        }
+        if record(&mut self.by_address, address, self.lockout_after, self.lockout_duration) {
+            lockouts.push(Lockout::ADDRESS(address));
+        }
+
+        lockouts
+    }
+
+    pub(crate) fn record_success(&mut self, email: &str, address: IpAddr) {
+        self.by_email.remove(&email.to_lowercase());
+        self.by_address.remove(&address);
+    }
+}
+// Private
+impl LoginGuard {
+    fn allowed_at(&self, attempts: &Attempts) -> Option<Instant> {
+        if let Some(locked_until) = attempts.locked_until {
+            return Some(locked_until);
+        }
+        if attempts.failures == 0 {
+            return None;
+        }
+
+        let backoff = self.backoff_base
+            .saturating_mul(2u32.saturating_pow(attempts.failures - 1))
+            .min(self.backoff_max);
+
+        Some(attempts.last_failure + backoff)
+    }
+
+    // Forgetting entries whose lockout ended or that haven't failed in a while.
+    fn prune(&mut self) {
+        let now = Instant::now();
+        let lockout_duration = self.lockout_duration;
+        let keep = |attempts: &mut Attempts| match attempts.locked_until {
+            Some(locked_until) => locked_until > now,
+            None => now.duration_since(attempts.last_failure) < lockout_duration,
+        };
+
+        self.by_email.retain(|_, attempts| keep(attempts));
+        self.by_address.retain(|_, attempts| keep(attempts));
+    }
+}
+
+// Session::LOGIN and the HTTP API both log in through here.
+// Every failure looks the same to the client whether the email exists or not.
+pub(crate) async fn login(login_guard: &Mutex<LoginGuard>, mongo_db: &MongoDB, info: UserCreationInfo, address: IpAddr) -> Result<User, ServerError> {
+    let email = info.email.clone();
+    let result = try_login(login_guard, mongo_db, info, address).await;
+
+    audit::record(mongo_db, AuditEvent::with_result(
+        AuditAction::LOGIN,
+        result.as_ref().ok().map(|user| user.uuid().to_string()),
+        Some(email),
+        Some(address),
+        &result,
+    )).await;
+
+    result
+}
+
+async fn try_login(login_guard: &Mutex<LoginGuard>, mongo_db: &MongoDB, info: UserCreationInfo, address: IpAddr) -> Result<User, ServerError> {
+    let email = info.email.clone();
+
+    let attempt = login_guard.lock()
+        .map_err(|e| ServerError::INTERNAL(e.to_string()))?
+        .begin_attempt(&email, address);
+    let lockouts = attempt.map_err(ServerError::RATE_LIMITED)?;
+
+    let result = mongo_db.login(info).await.map_err(ServerError::from);
+    if let Ok(mut login_guard) = login_guard.lock() {
+        match &result {
+            // A banned or suspended account still gave the right password.
+            Ok(_)
+            | Err(ServerError::ACCOUNT_BANNED(_))
+            | Err(ServerError::ACCOUNT_SUSPENDED(_, _)) => login_guard.record_success(&email, address),
+            Err(ServerError::INVALID_CREDENTIALS) => (),
+            Err(_) => login_guard.cancel_attempt(&email, address),
+        }
+    }
+
+    if let Err(ServerError::INVALID_CREDENTIALS) = result {
+        record_lockouts(mongo_db, lockouts, address).await;
+    }
+
+    result
+}
+
+// Session::SIGN_UP goes through the same guard, an email that is already taken counts as a failed login would.
+// Otherwise signing up would tell anyone which emails have an account, as fast as they can ask.
+pub(crate) async fn sign_up(login_guard: &Mutex<LoginGuard>, mongo_db: &MongoDB, info: UserCreationInfo, address: IpAddr) -> Result<User, ServerError> {
+    let email = info.email.clone();
+    let result = try_sign_up(login_guard, mongo_db, info, address).await;
+
+    audit::record(mongo_db, AuditEvent::with_result(
+        AuditAction::SIGN_UP,
+        result.as_ref().ok().map(|user| user.uuid().to_string()),
+        Some(email),
+        Some(address),
+        &result,
+    )).await;
+
+    result
+}
+
+async fn try_sign_up(login_guard: &Mutex<LoginGuard>, mongo_db: &MongoDB, info: UserCreationInfo, address: IpAddr) -> Result<User, ServerError> {
+    let email = info.email.clone();
+
+    let attempt = login_guard.lock()
+        .map_err(|e| ServerError::INTERNAL(e.to_string()))?
+        .begin_attempt(&email, address);
+    let lockouts = attempt.map_err(ServerError::RATE_LIMITED)?;
+
+    let result = mongo_db.sign_up(info).await.map_err(ServerError::from);
+    if let Ok(mut login_guard) = login_guard.lock() {
+        match &result {
+            Err(ServerError::USER_ALREADY_EXISTS) => (),
+            // Not a success for the email's logins, only the attempt is taken back.
+            _ => login_guard.cancel_attempt(&email, address),
+        }
+    }
+
+    if let Err(ServerError::USER_ALREADY_EXISTS) = result {
+        record_lockouts(mongo_db, lockouts, address).await;
+    }
+
+    result
+}
+
+async fn record_lockouts(mongo_db: &MongoDB, lockouts: Vec<Lockout>, address: IpAddr) {
+    for lockout in lockouts {
+        warn!("Login lockout: {:?}", lockout);
+        let target = match lockout {
+            Lockout::EMAIL(email) => email,
+            Lockout::ADDRESS(address) => address.to_string(),
+        };
+
+        audit::record(mongo_db, AuditEvent {
+            action: AuditAction::LOGIN_LOCKOUT,
+            actor: None,
+            target: Some(target),
+            address: Some(address),
+            outcome: "locked".to_string(),
+        }).await;
+    }
+}
+
+// Returns true when this failure locked the key out.
+fn record<K: Hash + Eq>(attempts: &mut HashMap<K, Attempts>, key: K, lockout_after: u32, lockout_duration: Duration) -> bool {
+    let now = Instant::now();
+    let attempts = attempts.entry(key).or_insert(Attempts {
+        failures: 0,
+        last_failure: now,
+        locked_until: None,
+    });
+
+    attempts.failures += 1;
+    attempts.last_failure = now;
+
+    if attempts.failures >= lockout_after && attempts.locked_until.is_none() {
+        attempts.locked_until = Some(now + lockout_duration);
+        return true;
+    }
+
+    false
+}
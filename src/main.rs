@@ -1,19 +1,22 @@
-use server_manager::ServerManager;
-use yapping_core::l3gion_rust::StdError;
+use std::{sync::Arc, time::Duration};
 
-mod mongo_db;
-mod server_manager;
-mod notification_manager;
-mod chat_manager;
-mod coms;
+use server::{config::ServerConfig, logging, mongo_db::MongoDBClient, server_manager::ServerManager, user_cache::UserCache};
+use yapping_core::l3gion_rust::StdError;
 
 #[tokio::main]
 async fn main() -> Result<(), StdError> {
-    if cfg!(debug_assertions) {
-        std::env::set_var("LOG", "4");
+    let config = ServerConfig::from_env();
+    logging::init(&config);
+
+    if std::env::args().any(|arg| arg == "--migrate-dry-run") {
+        for report in MongoDBClient::new(UserCache::new(0, Duration::ZERO), Arc::default()).await?.migrate(true).await? {
+            println!("{:>4} {:<32} {} documents", report.version, report.name, report.documents);
+        }
+
+        return Ok(());
     }
 
-    let manager = ServerManager::new().await?;
+    let manager = ServerManager::new(config).await?;
     manager.run().await?;
     
     Ok(())
@@ -0,0 +1,115 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Duration};
+
+// Upper bounds in seconds of the database latency buckets.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // Not cumulative, one per bucket plus one past the last.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        let bucket = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+// Shared by every task of the server, rendered by GET /metrics in the Prometheus text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections_open: AtomicU64,
+    connections_accepted: AtomicU64,
+    connections_refused: AtomicU64,
+    // Per MessageKind.
+    messages: Mutex<HashMap<&'static str, u64>>,
+    // Per MongoDB method.
+    db_latency: Mutex<HashMap<&'static str, Histogram>>,
+    broadcast_lag_events: AtomicU64,
+    broadcast_lagged_notifications: AtomicU64,
+    retried_messages: AtomicU64,
+}
+impl Metrics {
+    pub(crate) fn connection_accepted(&self, open: usize) {
+        self.connections_accepted.fetch_add(1, Ordering::Relaxed);
+        self.connections_open.store(open as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self, open: usize) {
+        self.connections_open.store(open as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_refused(&self) {
+        self.connections_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_handled(&self, kind: &'static str) {
+        if let Ok(mut messages) = self.messages.lock() {
+            *messages.entry(kind).or_default() += 1;
+        }
+    }
+
+    pub(crate) fn db_call(&self, method: &'static str, elapsed: Duration) {
+        if let Ok(mut db_latency) = self.db_latency.lock() {
+            db_latency.entry(method).or_default().observe(elapsed.as_secs_f64());
+        }
+    }
+
+    // A chat receiver fell behind and skipped this many notifications.
+    pub(crate) fn broadcast_lagged(&self, skipped: u64) {
+        self.broadcast_lag_events.fetch_add(1, Ordering::Relaxed);
+        self.broadcast_lagged_notifications.fetch_add(skipped, Ordering::Relaxed);
+    }
+
+    pub(crate) fn message_retried(&self) {
+        self.retried_messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+
+        gauge(&mut out, "yapping_connections_open", "WebSocket connections currently open.", self.connections_open.load(Ordering::Relaxed));
+        counter(&mut out, "yapping_connections_accepted_total", "WebSocket connections accepted.", self.connections_accepted.load(Ordering::Relaxed));
+        counter(&mut out, "yapping_connections_refused_total", "WebSocket connections refused over the connection limits.", self.connections_refused.load(Ordering::Relaxed));
+        counter(&mut out, "yapping_broadcast_lag_events_total", "Times a chat receiver fell behind its broadcast channel.", self.broadcast_lag_events.load(Ordering::Relaxed));
+        counter(&mut out, "yapping_broadcast_lagged_notifications_total", "Chat notifications skipped by lagging receivers.", self.broadcast_lagged_notifications.load(Ordering::Relaxed));
+        counter(&mut out, "yapping_retried_messages_total", "Unanswered messages sent again to clients.", self.retried_messages.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP yapping_messages_handled_total Client messages handled, per kind.");
+        let _ = writeln!(out, "# TYPE yapping_messages_handled_total counter");
+        if let Ok(messages) = self.messages.lock() {
+            for (kind, count) in messages.iter().collect::<BTreeMap<_, _>>() {
+                let _ = writeln!(out, "yapping_messages_handled_total{{kind=\"{kind}\"}} {count}");
+            }
+        }
+
+        let _ = writeln!(out, "# HELP yapping_db_latency_seconds Latency of the database calls, per MongoDB method.");
+        let _ = writeln!(out, "# TYPE yapping_db_latency_seconds histogram");
+        if let Ok(db_latency) = self.db_latency.lock() {
+            for (method, histogram) in db_latency.iter().collect::<BTreeMap<_, _>>() {
+                let mut cumulative = 0;
+                for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                    cumulative += count;
+                    let _ = writeln!(out, "yapping_db_latency_seconds_bucket{{method=\"{method}\",le=\"{bound}\"}} {cumulative}");
+                }
+                let _ = writeln!(out, "yapping_db_latency_seconds_bucket{{method=\"{method}\",le=\"+Inf\"}} {}", histogram.count);
+                let _ = writeln!(out, "yapping_db_latency_seconds_sum{{method=\"{method}\"}} {}", histogram.sum);
+                let _ = writeln!(out, "yapping_db_latency_seconds_count{{method=\"{method}\"}} {}", histogram.count);
+            }
+        }
+
+        out
+    }
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter\n{name} {value}");
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}");
+}
@@ -0,0 +1,167 @@
+use futures::StreamExt;
+use mongodb::{bson::{doc, Bson, DateTime, Document}, Collection, Database};
+use tracing::{info, warn};
+use yapping_core::{client_server_coms::{DbNotification, Notification, NotificationType}, l3gion_rust::StdError};
+
+const SCHEMA_VERSION: &str = "schema_version";
+
+// Every step selects only the documents it still has to change, so running it twice is harmless.
+// Steps are applied in order and must never be reordered or edited once released, only appended.
+struct Migration {
+    version: i64,
+    name: &'static str,
+    collection: &'static str,
+    filter: fn() -> Document,
+    change: Change,
+}
+
+#[allow(non_camel_case_types)]
+enum Change {
+    // The same update for every selected document.
+    UPDATE(fn() -> Document),
+    // An update computed from each selected document, None leaves it untouched.
+    REWRITE(fn(&Document) -> Option<Document>),
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "users_default_friends",
+        collection: "Users",
+        filter: || doc! { "friends": { "$exists": false } },
+        change: Change::UPDATE(|| doc! { "$set": { "friends": [] } }),
+    },
+    Migration {
+        version: 2,
+        name: "chats_default_messages",
+        collection: "Chats",
+        filter: || doc! { "messages": { "$exists": false } },
+        change: Change::UPDATE(|| doc! { "$set": { "messages": [] } }),
+    },
+    Migration {
+        version: 3,
+        name: "users_default_blocked",
+        collection: "Users",
+        filter: || doc! { "blocked": { "$exists": false } },
+        change: Change::UPDATE(|| doc! { "$set": { "blocked": [] } }),
+    },
+    Migration {
+        version: 4,
+        name: "notifications_created_at",
+        collection: "Notifications",
+        filter: || doc! { "created_at": { "$exists": false } },
+        change: Change::UPDATE(|| doc! { "$currentDate": { "created_at": true } }),
+    },
+    Migration {
+        version: 5,
+        name: "friend_requests_request_sender",
+        collection: "Notifications",
+        filter: || doc! { "request_sender": { "$exists": false } },
+        change: Change::REWRITE(friend_request_sender),
+    },
+];
+
+pub struct MigrationReport {
+    pub version: i64,
+    pub name: &'static str,
+    pub documents: u64,
+}
+
+pub(crate) async fn current_version(db: &Database) -> Result<i64, StdError> {
+    let mut applied = db.collection::<Document>(SCHEMA_VERSION)
+        .find(doc! {})
+        .sort(doc! { "_id": -1 })
+        .limit(1)
+        .await?;
+
+    match applied.next().await {
+        Some(step) => Ok(step?.get_i64("_id")?),
+        None => Ok(0),
+    }
+}
+
+// Applies every pending migration in order, or only counts the documents each one would touch when dry_run is set.
+pub(crate) async fn run(db: &Database, dry_run: bool) -> Result<Vec<MigrationReport>, StdError> {
+    let version = current_version(db).await?;
+    let mut reports = Vec::new();
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let collection = db.collection::<Document>(migration.collection);
+
+        let documents = if dry_run {
+            let count = match migration.change {
+                Change::UPDATE(_) => collection.count_documents((migration.filter)()).await?,
+                Change::REWRITE(rewrite) => rewrites(&collection, (migration.filter)(), rewrite).await?.len() as u64,
+            };
+            info!("[dry-run] Migration {} ({}) would touch {} documents in {}", migration.version, migration.name, count, migration.collection);
+
+            count
+        }
+        else {
+            let modified = apply(&collection, migration).await
+                .map_err(|e| format!("Migration {} ({}) failed: {e}", migration.version, migration.name))?;
+
+            db.collection::<Document>(SCHEMA_VERSION).insert_one(doc! {
+                "_id": migration.version,
+                "name": migration.name,
+                "applied_at": DateTime::now(),
+                "modified": modified as i64,
+            }).await?;
+            info!("Applied migration {} ({}), {} documents modified", migration.version, migration.name, modified);
+
+            modified
+        };
+
+        reports.push(MigrationReport {
+            version: migration.version,
+            name: migration.name,
+            documents,
+        });
+    }
+
+    if reports.is_empty() {
+        info!("Database schema is up to date at version {version}");
+    }
+    else if dry_run {
+        warn!("Dry-run: {} pending migrations were not applied", reports.len());
+    }
+
+    Ok(reports)
+}
+
+async fn apply(collection: &Collection<Document>, migration: &Migration) -> Result<u64, StdError> {
+    match migration.change {
+        Change::UPDATE(update) => Ok(collection.update_many((migration.filter)(), update()).await?.modified_count),
+        Change::REWRITE(rewrite) => {
+            let mut modified = 0;
+            for (id, update) in rewrites(collection, (migration.filter)(), rewrite).await? {
+                modified += collection.update_one(doc! { "_id": id }, update).await?.modified_count;
+            }
+
+            Ok(modified)
+        },
+    }
+}
+
+// The _id and update of every selected document the rewrite changes.
+async fn rewrites(collection: &Collection<Document>, filter: Document, rewrite: fn(&Document) -> Option<Document>) -> Result<Vec<(Bson, Document)>, StdError> {
+    let mut documents = collection.find(filter).await?;
+    let mut updates = Vec::new();
+    while let Some(document) = documents.next().await {
+        let document = document?;
+        if let (Some(id), Some(update)) = (document.get("_id"), rewrite(&document)) {
+            updates.push((id.clone(), update));
+        }
+    }
+
+    Ok(updates)
+}
+
+// Friend requests stored before request_sender existed, their sender only lives inside the notification type.
+fn friend_request_sender(document: &Document) -> Option<Document> {
+    let db_notification = mongodb::bson::from_document::<DbNotification>(document.clone()).ok()?;
+    match Notification::from(db_notification).ok()?.notification_type {
+        NotificationType::FRIEND_REQUEST(sender, _) => Some(doc! { "$set": { "request_sender": sender.to_string() } }),
+        _ => None,
+    }
+}
@@ -1,21 +1,38 @@
-use std::{future::IntoFuture, process::ExitStatus};
-use mongodb::bson::doc;
-use yapping_core::{chat::{Chat, DbChat}, client_server_coms::{DbNotification, Notification}, l3gion_rust::{rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator}, StdError, UUID}, message::{DbMessage, Message}, user::{DbUser, User, UserCreationInfo}};
+use std::{collections::HashMap, convert::Infallible, future::IntoFuture, process::ExitStatus, sync::{Arc, Mutex, MutexGuard, PoisonError}, time::{Duration, Instant}};
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+use yapping_core::{chat::{Chat, DbChat}, client_server_coms::{DbNotification, Notification, NotificationType}, l3gion_rust::{rayon::iter::{IntoParallelRefIterator, ParallelIterator}, StdError, UUID}, message::{DbMessage, Message}, user::{DbUser, User, UserCreationInfo}};
 use futures::StreamExt;
-use mongodb::{Client, Database};
+use mongodb::{options::IndexOptions, Client, Database, IndexModel};
 use std::io::Error as IoError;
 use std::process::Command;
 use tokio::task;
 
+use crate::{audit::AuditEvent, metrics::Metrics, migrations::{self, MigrationReport}, server_error::ServerError, user_cache::UserCache};
+
 const MONGO_DATA: &str = "mongo_db/data";
 const MONGO_LOG: &str = "mongo_db/log";
+const DATABASE_NAME: &str = "yapping_db";
+
+const USERS: &str = "Users";
+const NOTIFICATIONS: &str = "Notifications";
+const CHATS: &str = "Chats";
+const QUARANTINE: &str = "quarantine";
+const MODERATION_EVENTS: &str = "moderation_events";
+const AUDIT_LOG: &str = "audit_log";
+const SESSIONS: &str = "sessions";
 
-pub(crate) struct MongoDBClient {
-    _db_thread: tokio::task::JoinHandle<Result<ExitStatus, IoError>>,
+pub struct MongoDBClient {
+    _db_thread: Option<tokio::task::JoinHandle<Result<ExitStatus, IoError>>>,
     mongo_client: Client,
+    // Shared by every MongoDB handed out.
+    user_cache: Arc<Mutex<UserCache>>,
+    metrics: Arc<Metrics>,
 }
 impl MongoDBClient {
-    pub(crate) async fn new() -> Result<Self, StdError> {
+    pub async fn new(user_cache: UserCache, metrics: Arc<Metrics>) -> Result<Self, StdError> {
         std::fs::create_dir_all(MONGO_DATA).map_err(|_| "Failed to create MongoDB data directory!")?;
 
         let _db_thread = task::spawn_blocking(move || {
@@ -28,52 +45,101 @@ impl MongoDBClient {
                 .and_then(|mut child| child.wait())
         });
 
+        Ok(Self {
+            _db_thread: Some(_db_thread),
+            mongo_client: Self::client().await?,
+            user_cache: Arc::new(Mutex::new(user_cache)),
+            metrics,
+        })
+    }
+
+    // Connects to an already running mongod, used by tools that run next to the server.
+    // Those never cache users, the server's cache can't see their writes anyway.
+    pub async fn connect() -> Result<Self, StdError> {
+        Ok(Self {
+            _db_thread: None,
+            mongo_client: Self::client().await?,
+            user_cache: Arc::new(Mutex::new(UserCache::new(0, Duration::ZERO))),
+            metrics: Arc::default(),
+        })
+    }
+    
+    pub fn get_database(&self) -> MongoDB {
+        MongoDB(self.mongo_client.database(DATABASE_NAME), Arc::clone(&self.user_cache), Arc::clone(&self.metrics))
+    }
+
+    pub async fn migrate(&self, dry_run: bool) -> Result<Vec<MigrationReport>, StdError> {
+        migrations::run(&self.mongo_client.database(DATABASE_NAME), dry_run).await
+    }
+}
+// Private
+impl MongoDBClient {
+    async fn client() -> Result<Client, StdError> {
         let client_options = mongodb::options::ClientOptions::parse("mongodb://localhost:27017/?directConnection=true")
             .await
             .map_err(|e| format!("Failed to parse client options: {}", e))?;
         
         let mongo_client = Client::with_options(client_options)
             .map_err(|e| format!("Failed to create MongoDB client: {}", e))?;
-        
-        Ok(Self {
-            _db_thread,
-            mongo_client,
-        })
+
+        Ok(mongo_client)
     }
-    
-    pub(crate) fn get_database(&self) -> MongoDB {
-        MongoDB(self.mongo_client.database("yapping_db"))
+}
+
+// Records how long a MongoDB method took once it returns, however it returns.
+struct DbTimer {
+    metrics: Arc<Metrics>,
+    method: &'static str,
+    started: Instant,
+}
+impl Drop for DbTimer {
+    fn drop(&mut self) {
+        self.metrics.db_call(self.method, self.started.elapsed());
     }
 }
 
+// A page of a query's results, only those are loaded from MongoDB.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Paging {
+    pub(crate) skip: u64,
+    pub(crate) limit: i64,
+}
+
 #[derive(Debug, Clone)]
-pub(crate) struct MongoDB(Database);
+pub struct MongoDB(Database, Arc<Mutex<UserCache>>, Arc<Metrics>);
 impl MongoDB {
+    // Readiness check, fails when mongod can't be reached.
+    pub(crate) async fn ping(&self) -> Result<(), StdError> {
+        let _timer = self.timer("ping");
+        self.0.run_command(doc! { "ping": 1 }).await?;
+
+        Ok(())
+    }
+
     pub(crate) async fn login(&self, info: UserCreationInfo) -> Result<User, StdError> {
-        let db_user = self.get_db_user(doc! { 
+        let _timer = self.timer("login");
+        let filter = doc! {
             "email": info.email.clone(),
             "password": info.password.to_string(),
-        }).await?;
-
-        let mut friends = Vec::with_capacity(db_user.friends().len());
-        for friend_uuid in db_user.friends() {
-            friends.push(User::from(self.get_db_user(doc! {
-                "_id": friend_uuid
-            }).await?)?);
-        }
+        };
+        let db_user = self.find_one_or_quarantine(USERS, filter, Ok::<DbUser, Infallible>).await?
+            .ok_or(ServerError::INVALID_CREDENTIALS)?;
 
+        let friends = self.get_striped_users(db_user.friends()).await?;
         let mut user = User::from(db_user)?;
         user.set_friends(friends);
+        self.check_moderation(user.uuid()).await?;
 
         Ok(user)
     }
 
     pub(crate) async fn sign_up(&self, info: UserCreationInfo) -> Result<User, StdError> {
+        let _timer = self.timer("sign_up");
         if self.get_db_user(doc! { "email": info.email.clone() }).await.is_ok() {
-            return Err("User already exist!".into());
+            return Err(ServerError::USER_ALREADY_EXISTS.into());
         }
         else if info.tag.is_empty() || info.email.is_empty() || !info.password.is_valid() {
-            return Err("Please fill all the fields!".into());
+            return Err(ServerError::MISSING_FIELDS.into());
         }
         
         let db_user = DbUser::new(info);
@@ -84,16 +150,114 @@ impl MongoDB {
         Ok(User::from(db_user)?)
     }
 
+    // Fails with the reason when the account is banned or still suspended, or when it doesn't exist.
+    pub(crate) async fn check_moderation(&self, user_uuid: UUID) -> Result<(), StdError> {
+        let _timer = self.timer("check_moderation");
+        let Some(user) = self.0.collection::<Document>(USERS)
+            .find_one(doc! { "_id": user_uuid.to_string() })
+            .projection(doc! { "banned": 1, "ban_reason": 1, "suspended_until": 1, "suspension_reason": 1 })
+            .await? 
+        else {
+            return Err(ServerError::USER_NOT_FOUND.into());
+        };
+
+        if user.get_bool("banned").unwrap_or(false) {
+            return Err(ServerError::ACCOUNT_BANNED(user.get_str("ban_reason").unwrap_or_default().to_string()).into());
+        }
+        if let Ok(until) = user.get_datetime("suspended_until") {
+            if *until > DateTime::now() {
+                return Err(ServerError::ACCOUNT_SUSPENDED(
+                    until.try_to_rfc3339_string().unwrap_or_default(),
+                    user.get_str("suspension_reason").unwrap_or_default().to_string(),
+                ).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // Bearer tokens of the HTTP API, returns when the session expires.
+    // Only a hash of the token is stored, so a leaked database can't be replayed against the API.
+    pub(crate) async fn create_session(&self, user: UUID, token: &str, ttl: Duration) -> Result<DateTime, StdError> {
+        let _timer = self.timer("create_session");
+        let now = DateTime::now();
+        let expires_at = DateTime::from_millis(now.timestamp_millis() + ttl.as_millis() as i64);
+
+        self.0.collection::<Document>(SESSIONS).insert_one(doc! {
+            "_id": session_id(token),
+            "user_id": user.to_string(),
+            "created_at": now,
+            "expires_at": expires_at,
+        }).await?;
+
+        Ok(expires_at)
+    }
+
+    // The stripped user the token belongs to, while the session hasn't expired.
+    pub(crate) async fn get_session_user(&self, token: &str) -> Result<Option<User>, StdError> {
+        let _timer = self.timer("get_session_user");
+        let Some(session) = self.0.collection::<Document>(SESSIONS)
+            .find_one(doc! { "_id": session_id(token), "expires_at": { "$gt": DateTime::now() } })
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let user_id = session.get_str("user_id")?.to_string();
+        Ok(self.get_striped_users(&[user_id]).await?.into_iter().next())
+    }
+
+    pub(crate) async fn remove_session(&self, token: &str) -> Result<(), StdError> {
+        let _timer = self.timer("remove_session");
+        self.0.collection::<Document>(SESSIONS).delete_one(doc! { "_id": session_id(token) }).await?;
+
+        Ok(())
+    }
+
+    // Lets MongoDB delete sessions once they expire.
+    pub(crate) async fn create_session_index(&self) -> Result<(), StdError> {
+        let _timer = self.timer("create_session_index");
+        self.0.collection::<Document>(SESSIONS).create_index(
+            IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+                .build()
+        ).await?;
+
+        Ok(())
+    }
+
+    // Bans and suspensions applied since the last call, as (user id, reason).
+    pub(crate) async fn take_moderation_events(&self) -> Result<Vec<(String, String)>, StdError> {
+        let _timer = self.timer("take_moderation_events");
+        let events = self.0.collection::<Document>(MODERATION_EVENTS)
+            .find(doc! {})
+            .sort(doc! { "created_at": 1 })
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut result = Vec::with_capacity(events.len());
+        for event in events {
+            if let Some(id) = event.get("_id") {
+                self.0.collection::<Document>(MODERATION_EVENTS).delete_one(doc! { "_id": id }).await?;
+            }
+
+            result.push((
+                event.get_str("user").unwrap_or_default().to_string(),
+                event.get_str("reason").unwrap_or_default().to_string(),
+            ));
+        }
+
+        Ok(result)
+    }
+
     pub(crate) async fn get_full_user(&self, user_uuid: UUID) -> Result<User, StdError> {
+        let _timer = self.timer("get_full_user");
         let db_user = self.get_db_user(doc! { "_id": user_uuid.to_string() }).await?;
         
-        let mut friends = Vec::with_capacity(db_user.friends().len());
-        for friend_uuid in db_user.friends() {
-            friends.push(User::from(self.get_db_user(doc! {
-                "_id": friend_uuid
-            }).await?)?);
-        }
-
+        let friends = self.get_striped_users(db_user.friends()).await?;
         let mut user = User::from(db_user)?;
         user.set_friends(friends);
         
@@ -101,61 +265,131 @@ impl MongoDB {
     }
 
     pub(crate) async fn change_user_tag(&self, user: UUID, tag: String) -> Result<(), StdError> {
+        let _timer = self.timer("change_user_tag");
         self.user_collection().find_one_and_update(doc! { "_id": user.to_string() }, doc! { "$set": { "tag": tag} })
             .await
             .map_err(|e| e.to_string())?;
+        self.user_cache().invalidate(&user.to_string());
         
         Ok(())
     }
 
     pub(crate) async fn insert_friend(&self, user: UUID, friend: UUID) -> Result<mongodb::results::UpdateResult, mongodb::error::Error> {
-        self.user_collection()
-        .update_one(doc! { "_id": user.to_string() }, doc! { "$addToSet": { "friends": friend.to_string() } } ).await
+        let _timer = self.timer("insert_friend");
+        let result = self.user_collection()
+            .update_one(doc! { "_id": user.to_string() }, doc! { "$addToSet": { "friends": friend.to_string() } } ).await;
+        self.user_cache().invalidate(&user.to_string());
+
+        result
     }
 
     pub(crate) async fn remove_friend(&self, user: UUID, friend: UUID) -> Result<mongodb::results::UpdateResult, mongodb::error::Error> {
+        let _timer = self.timer("remove_friend");
+        let result = self.user_collection()
+            .update_one(doc! { "_id": user.to_string() }, doc! { "$pull": { "friends": friend.to_string() }}).await;
+        self.user_cache().invalidate(&user.to_string());
+
+        result
+    }
+
+    // The audit trail is append-only, nothing ever updates or deletes from it.
+    pub(crate) async fn insert_audit_event(&self, event: &AuditEvent) -> Result<(), StdError> {
+        let _timer = self.timer("insert_audit_event");
+        self.0.collection::<Document>(AUDIT_LOG).insert_one(event.to_document()).await?;
+
+        Ok(())
+    }
+
+    // For get_audit_events, which filters by user and time.
+    pub(crate) async fn create_audit_indexes(&self) -> Result<(), StdError> {
+        let _timer = self.timer("create_audit_indexes");
+        self.0.collection::<Document>(AUDIT_LOG).create_indexes([
+            IndexModel::builder().keys(doc! { "actor": 1, "timestamp": 1 }).build(),
+            IndexModel::builder().keys(doc! { "target": 1, "timestamp": 1 }).build(),
+            IndexModel::builder().keys(doc! { "timestamp": 1 }).build(),
+        ]).await?;
+
+        Ok(())
+    }
+
+    pub(crate) async fn block_user(&self, user: UUID, blocked: UUID) -> Result<mongodb::results::UpdateResult, mongodb::error::Error> {
+        let _timer = self.timer("block_user");
+        self.user_collection()
+            .update_one(doc! { "_id": user.to_string() }, doc! { "$addToSet": { "blocked": blocked.to_string() } }).await
+    }
+
+    pub(crate) async fn unblock_user(&self, user: UUID, blocked: UUID) -> Result<mongodb::results::UpdateResult, mongodb::error::Error> {
+        let _timer = self.timer("unblock_user");
         self.user_collection()
-            .update_one(doc! { "_id": user.to_string() }, doc! { "$pull": { "friends": friend.to_string() }}).await
+            .update_one(doc! { "_id": user.to_string() }, doc! { "$pull": { "blocked": blocked.to_string() } }).await
+    }
+
+    // Ids of the users blocked by `user`, as stored in the database.
+    pub(crate) async fn get_blocked_users(&self, user: UUID) -> Result<Vec<String>, StdError> {
+        let _timer = self.timer("get_blocked_users");
+        let blocked = self.0.collection::<Document>(USERS)
+            .find_one(doc! { "_id": user.to_string() })
+            .projection(doc! { "blocked": 1 })
+            .await?
+            .and_then(|user| user.get_array("blocked").ok().cloned())
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| match id {
+                Bson::String(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+
+        Ok(blocked)
+    }
+
+    pub(crate) async fn is_blocked(&self, blocker: UUID, blocked: UUID) -> Result<bool, StdError> {
+        let _timer = self.timer("is_blocked");
+        let count = self.0.collection::<Document>(USERS)
+            .count_documents(doc! { "_id": blocker.to_string(), "blocked": blocked.to_string() })
+            .await?;
+
+        Ok(count > 0)
     }
 
     pub(crate) async fn insert_notification(&self, user: UUID, notification: &Notification) -> Result<(), StdError> {
-        let db_notification = DbNotification::new(user, notification);
-        self.notification_collection().insert_one(db_notification).await?;
+        let _timer = self.timer("insert_notification");
+        let mut document = mongodb::bson::to_document(&DbNotification::new(user, notification))?;
+        // Extra fields next to the DbNotification ones, used for expiry and outgoing request lookups.
+        document.insert("created_at", DateTime::now());
+        if let NotificationType::FRIEND_REQUEST(sender, _) = notification.notification_type {
+            document.insert("request_sender", sender.to_string());
+        }
+
+        self.0.collection::<Document>(NOTIFICATIONS).insert_one(document).await?;
 
         Ok(())
     }
 
+    // A duplicate is replaced, _id can't be updated so it is removed and the new one inserted.
+    // Returns true when it replaced one.
     pub(crate) async fn insert_non_duplicant_notification(&self, user: UUID, notification: &Notification) -> Result<bool, StdError> {
-        if let Some(existing) = self.get_user_notifications(user).await?
+        let _timer = self.timer("insert_non_duplicant_notification");
+        let existing = self.get_user_notifications(user).await?
             .par_iter()
             .find_any(|n| n.notification_type == notification.notification_type)
-        {
-            self.notification_collection().update_one(
-                doc! { "_id": existing.uuid().to_string() },
-                doc! { "$set": { "_id": notification.uuid().to_string() } },
-            ).await?;
+            .map(|n| n.uuid());
 
-            Ok(true)
+        if let Some(existing) = existing {
+            self.remove_notification(existing).await?;
         }
-        else {
-            self.insert_notification(user, notification).await?;
+        self.insert_notification(user, notification).await?;
 
-            Ok(false)
-        }
+        Ok(existing.is_some())
     }
 
-    pub(crate) async fn get_user_notifications(&self, user_uuid: UUID) -> mongodb::error::Result<Vec<Notification>> {
-        let notifications = self.notification_collection().find(doc! { "user": user_uuid.to_string() }).await?
-            .collect::<Vec<Result<DbNotification, _>>>().await
-            .into_par_iter()
-            .filter_map(|db_n| db_n.ok())
-            .filter_map(|db_n| Notification::from(db_n).ok())
-            .collect();
-        
-        Ok(notifications)
+    pub(crate) async fn get_user_notifications(&self, user_uuid: UUID) -> Result<Vec<Notification>, StdError> {
+        let _timer = self.timer("get_user_notifications");
+        self.find_or_quarantine(NOTIFICATIONS, doc! { "user": user_uuid.to_string() }, Notification::from).await
     }
     
     pub(crate) async fn get_user_friend_requests(&self, user_uuid: UUID) -> Result<Vec<Notification>, StdError> {
+        let _timer = self.timer("get_user_friend_requests");
         let fr = self.get_user_notifications(user_uuid).await?
             .into_iter()
             .filter(|nt| match nt.notification_type {
@@ -167,70 +401,177 @@ impl MongoDB {
         Ok(fr)
     }
 
+    // Oldest first. Only friend requests carry request_sender, backfilled by migration 5 for older ones.
+    pub(crate) async fn get_user_friend_requests_page(&self, user_uuid: UUID, paging: Paging) -> Result<Vec<Notification>, StdError> {
+        let _timer = self.timer("get_user_friend_requests_page");
+        let filter = doc! { "user": user_uuid.to_string(), "request_sender": { "$exists": true } };
+        self.find_page_or_quarantine(NOTIFICATIONS, filter, doc! { "created_at": 1, "_id": 1 }, paging, Notification::from).await
+    }
+
+    // Friend requests `user_uuid` sent that are still pending, oldest first.
+    pub(crate) async fn get_user_sent_friend_requests(&self, user_uuid: UUID, paging: Paging) -> Result<Vec<Notification>, StdError> {
+        let _timer = self.timer("get_user_sent_friend_requests");
+        let filter = doc! { "request_sender": user_uuid.to_string() };
+        self.find_page_or_quarantine(NOTIFICATIONS, filter, doc! { "created_at": 1, "_id": 1 }, paging, Notification::from).await
+    }
+
+    // Returns false when there was no pending request from `sender` to `receiver`.
+    pub(crate) async fn remove_friend_request(&self, sender: UUID, receiver: UUID) -> Result<bool, StdError> {
+        let _timer = self.timer("remove_friend_request");
+        let mut removed = false;
+
+        for request in self.get_user_friend_requests(receiver).await? {
+            if let NotificationType::FRIEND_REQUEST(request_sender, request_receiver) = request.notification_type {
+                if request_sender == sender && request_receiver == receiver {
+                    self.remove_notification(request.uuid()).await?;
+                    removed = true;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+
+    pub(crate) async fn remove_expired_friend_requests(&self, ttl: std::time::Duration) -> Result<u64, StdError> {
+        let _timer = self.timer("remove_expired_friend_requests");
+        let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - ttl.as_millis() as i64);
+        let mut removed = 0;
+
+        // Only friend requests carry request_sender, backfilled by migration 5 for older ones.
+        let filter = doc! { "created_at": { "$lt": cutoff }, "request_sender": { "$exists": true } };
+        for notification in self.find_or_quarantine(NOTIFICATIONS, filter, Notification::from).await? {
+            if let NotificationType::FRIEND_REQUEST(_, _) = notification.notification_type {
+                removed += self.remove_notification(notification.uuid()).await?.deleted_count;
+            }
+        }
+
+        Ok(removed)
+    }
+
     pub(crate) async fn remove_notification(&self, notification_uuid: UUID) -> Result<mongodb::results::DeleteResult, mongodb::error::Error> {
+        let _timer = self.timer("remove_notification");
         self.notification_collection().delete_one(doc! { "_id": notification_uuid.to_string() }).await
     }
     
     pub(crate) async fn new_chat(&self, chat: &Chat) -> Result<(), StdError> {
+        let _timer = self.timer("new_chat");
         let db_chat = DbChat::new(chat.uuid(), chat.tag(), chat.users());
         if self.chat_collection().find_one(doc! { "users": { "$all": db_chat.users() } }).await?.is_none() {
             self.chat_collection().insert_one(db_chat).await?;
             return Ok(())
         }
         
-        Err("Chat alwready exists!".into())
+        Err(ServerError::CHAT_ALREADY_EXISTS.into())
     }
     
     pub(crate) async fn remove_chat(&self, chat_uuid: UUID) -> Result<mongodb::results::DeleteResult, mongodb::error::Error> {
+        let _timer = self.timer("remove_chat");
         self.chat_collection()
             .delete_one(doc! { "_id": chat_uuid.to_string()}).await
     }
 
     pub(crate) async fn get_chat(&self, chat_uuid: UUID) -> Result<Chat, StdError> {
-        Chat::from(self.chat_collection().find_one(doc! { "_id": chat_uuid.to_string() }).await?.ok_or("In MongoDB::get_chat: Failed to find Chat!")?)
+        let _timer = self.timer("get_chat");
+        Ok(self.find_one_or_quarantine(CHATS, doc! { "_id": chat_uuid.to_string() }, Chat::from).await?.ok_or(ServerError::CHAT_NOT_FOUND)?)
     }
 
-    pub(crate) async fn get_user_chats(&self, user_uuid: UUID) -> mongodb::error::Result<Vec<Chat>> {
-        let chats = self.chat_collection()
-            .find(doc! { "users": user_uuid.to_string() }).await?
-            .collect::<Vec<Result<DbChat, _>>>().await
-            .into_par_iter()
-            .filter_map(|db_c| db_c.ok())
-            .filter_map(|db_c| Chat::from(db_c).ok())
-            .collect();
-        
-        Ok(chats)
+    pub(crate) async fn get_user_chats(&self, user_uuid: UUID) -> Result<Vec<Chat>, StdError> {
+        let _timer = self.timer("get_user_chats");
+        self.find_or_quarantine(CHATS, doc! { "users": user_uuid.to_string() }, Chat::from).await
+    }
+
+    // Without their messages, those are paged through get_chat_messages.
+    pub(crate) async fn get_user_chats_page(&self, user_uuid: UUID, paging: Paging) -> Result<Vec<Chat>, StdError> {
+        let _timer = self.timer("get_user_chats_page");
+        self.aggregate_or_quarantine(CHATS, vec![
+            doc! { "$match": { "users": user_uuid.to_string() } },
+            doc! { "$sort": { "_id": 1 } },
+            doc! { "$skip": paging.skip as i64 },
+            doc! { "$limit": paging.limit },
+            doc! { "$set": { "messages": [] } },
+        ], Chat::from).await
     }
 
-    pub(crate) async fn insert_message(&self, chat_uuid: UUID, message: Message) -> Result<(), StdError> {
-        let doc_db_message = mongodb::bson::to_bson(&DbMessage::from(message))?;
+    // The chat with only the messages viewer may see, the ones from users it blocked are left out.
+    // Messages stored before their sender was recorded are always shown. Chat id as stored in the database.
+    // All of them oldest first, or a page of them newest first.
+    pub(crate) async fn get_chat_messages(&self, chat_id: String, viewer: UUID, paging: Option<Paging>) -> Result<Chat, StdError> {
+        let _timer = self.timer("get_chat_messages");
+        let blocked = self.get_blocked_users(viewer).await?;
+
+        let mut pipeline = vec![
+            doc! { "$match": { "_id": chat_id } },
+            doc! { "$set": {
+                "messages": { "$filter": {
+                    "input": { "$ifNull": ["$messages", []] },
+                    "cond": { "$not": [{ "$in": ["$$this.sent_by", blocked] }] },
+                }},
+            }},
+        ];
+        if let Some(paging) = paging {
+            pipeline.push(doc! { "$set": {
+                "messages": { "$slice": [{ "$reverseArray": "$messages" }, paging.skip as i64, paging.limit] },
+            }});
+        }
+
+        self.aggregate_or_quarantine(CHATS, pipeline, Chat::from).await?
+        .into_iter()
+        .next()
+        .ok_or(ServerError::CHAT_NOT_FOUND.into())
+    }
+
+    // The sender is the logged in user, recorded next to the message rather than trusted from it.
+    pub(crate) async fn insert_message(&self, chat_uuid: UUID, sender: UUID, message: Message) -> Result<(), StdError> {
+        let _timer = self.timer("insert_message");
+        let mut doc_db_message = mongodb::bson::to_document(&DbMessage::from(message))?;
+        doc_db_message.insert("sent_by", sender.to_string());
         self.chat_collection().update_one(doc! { "_id": chat_uuid.to_string() }, doc! { "$addToSet": { "messages": doc_db_message } }).await?;
         
         Ok(())
     }
 
     pub(crate) async fn query_by_tag(&self, tags: Vec<String>) -> Vec<User> {
-        let mut result = Vec::with_capacity(tags.len());
-        
-        for tag in tags {
-            if let Ok(user) = self.get_striped_user(doc! { "tag": tag }).await {
-                result.push(user);
+        let _timer = self.timer("query_by_tag");
+        let users = match self.find_or_quarantine(USERS, doc! { "tag": { "$in": tags } }, User::from).await {
+            Ok(users) => users,
+            Err(e) => {
+                error!("In MongoDB::query_by_tag: {e}");
+                return vec![];
             }
-        }
-        
-        result
+        };
+
+        let mut user_cache = self.user_cache();
+        users.into_iter()
+            .map(|mut user| {
+                user.strip_info();
+                user_cache.insert(user.uuid().to_string(), user.clone());
+
+                user
+            })
+            .collect()
     }
     
-    pub(crate) async fn query_contains_tag(&self, tag: String) -> Result<Vec<User>, StdError> {
-        let users = self.user_collection();
+    // Case-insensitive substring search, tags starting with the query come first.
+    pub(crate) async fn query_contains_tag(&self, requester: UUID, tag: String, page: u32, page_size: u32) -> Result<Vec<User>, StdError> {
+        let _timer = self.timer("query_contains_tag");
+        let blocked = self.get_blocked_users(requester).await?;
 
-        let users = users.find(doc! {
-            "tag": { "$regex": tag, "$options": "i" } // "i" option for case-insensitive search
-        }).await?
-        .collect::<Vec<Result<DbUser, _>>>().await
+        let users = self.aggregate_or_quarantine(USERS, vec![
+            doc! { "$match": {
+                "_id": { "$nin": blocked },
+                "tag": { "$regex": escape_regex(&tag), "$options": "i" },
+            }},
+            doc! { "$addFields": {
+                "search_rank": { "$cond": [
+                    { "$eq": [{ "$indexOfCP": [{ "$toLower": "$tag" }, tag.to_lowercase()] }, 0] }, 0, 1
+                ]},
+            }},
+            doc! { "$sort": { "search_rank": 1, "tag": 1, "_id": 1 } },
+            doc! { "$skip": page as i64 * page_size as i64 },
+            doc! { "$limit": page_size as i64 },
+            doc! { "$unset": "search_rank" },
+        ], User::from).await?
         .into_iter()
-        .filter_map(|db_user| db_user.ok())
-        .filter_map(|db_user| User::from(db_user).ok())
         .map(|mut user| { 
             user.strip_info();
             user
@@ -241,43 +582,417 @@ impl MongoDB {
     }
     
     pub(crate) async fn query_by_uuid(&self, uuids: Vec<UUID>) -> Vec<User> {
-        let mut result = Vec::with_capacity(uuids.len());
-        
-        for uuid in uuids {
-            if let Ok(user) = self.get_striped_user(doc! { "_id": uuid.to_string() }).await {
-                result.push(user);
-            }
+        self.query_by_ids(uuids.iter().map(|uuid| uuid.to_string()).collect()).await
+    }
+
+    // Ids as stored in the database.
+    pub(crate) async fn query_by_ids(&self, ids: Vec<String>) -> Vec<User> {
+        let _timer = self.timer("query_by_ids");
+        self.get_striped_users(&ids).await.unwrap_or_else(|e| {
+            error!("In MongoDB::query_by_ids: {e}");
+            vec![]
+        })
+    }
+}
+pub struct DatabaseStats {
+    pub users: u64,
+    pub banned_users: u64,
+    pub chats: u64,
+    pub messages: i64,
+    pub notifications: u64,
+    pub quarantined: u64,
+}
+
+// Admin, only used by the yapping_admin binary.
+impl MongoDB {
+    // Raw documents so operators see every stored field, passwords are never returned.
+    pub async fn find_users(&self, search: Option<&str>) -> Result<Vec<Document>, StdError> {
+        let filter = match search {
+            Some(search) => doc! { "$or": [
+                { "_id": search },
+                { "email": { "$regex": escape_regex(search), "$options": "i" } },
+                { "tag": { "$regex": escape_regex(search), "$options": "i" } },
+            ] },
+            None => doc! {},
+        };
+
+        let users = self.0.collection::<Document>(USERS)
+            .find(filter)
+            .projection(doc! { "password": 0 })
+            .sort(doc! { "tag": 1 })
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(users)
+    }
+
+    pub async fn reset_password(&self, email: &str, password: &str) -> Result<bool, StdError> {
+        let result = self.0.collection::<Document>(USERS)
+            .update_one(doc! { "email": email }, doc! { "$set": { "password": password } })
+            .await?;
+
+        Ok(result.matched_count > 0)
+    }
+
+    pub async fn set_banned(&self, user_id: &str, banned: bool, reason: &str) -> Result<bool, StdError> {
+        let update = if banned {
+            doc! { "$set": { "banned": true, "ban_reason": reason } }
+        } else {
+            doc! { "$set": { "banned": false }, "$unset": { "ban_reason": "" } }
+        };
+
+        let result = self.0.collection::<Document>(USERS)
+            .update_one(doc! { "_id": user_id }, update)
+            .await?;
+
+        if banned && result.matched_count > 0 {
+            self.insert_moderation_event(user_id, &format!("Account is banned: {reason}")).await?;
         }
-        
-        result
+
+        Ok(result.matched_count > 0)
+    }
+
+    // Passing None lifts the suspension.
+    pub async fn set_suspended(&self, user_id: &str, until: Option<DateTime>, reason: &str) -> Result<bool, StdError> {
+        let update = match until {
+            Some(until) => doc! { "$set": { "suspended_until": until, "suspension_reason": reason } },
+            None => doc! { "$unset": { "suspended_until": "", "suspension_reason": "" } },
+        };
+
+        let result = self.0.collection::<Document>(USERS)
+            .update_one(doc! { "_id": user_id }, update)
+            .await?;
+
+        if until.is_some() && result.matched_count > 0 {
+            self.insert_moderation_event(user_id, &format!("Account is suspended: {reason}")).await?;
+        }
+
+        Ok(result.matched_count > 0)
+    }
+
+    // Picked up by the server to disconnect the user's live connection.
+    async fn insert_moderation_event(&self, user_id: &str, reason: &str) -> Result<(), StdError> {
+        self.0.collection::<Document>(MODERATION_EVENTS).insert_one(doc! {
+            "user": user_id,
+            "reason": reason,
+            "created_at": DateTime::now(),
+        }).await?;
+
+        Ok(())
+    }
+
+    // Oldest first. The user matches entries where it is either the actor or the target.
+    pub async fn get_audit_events(&self, user: Option<&str>, since: Option<DateTime>, until: Option<DateTime>) -> Result<Vec<Document>, StdError> {
+        let mut filter = Document::new();
+        if let Some(user) = user {
+            filter.insert("$or", vec![doc! { "actor": user }, doc! { "target": user }]);
+        }
+
+        let mut timestamp = Document::new();
+        if let Some(since) = since {
+            timestamp.insert("$gte", since);
+        }
+        if let Some(until) = until {
+            timestamp.insert("$lt", until);
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+
+        let events = self.0.collection::<Document>(AUDIT_LOG)
+            .find(filter)
+            .sort(doc! { "timestamp": 1 })
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(events)
+    }
+
+    pub async fn force_delete_chat(&self, chat_id: &str) -> Result<bool, StdError> {
+        let result = self.0.collection::<Document>(CHATS)
+            .delete_one(doc! { "_id": chat_id })
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+
+    pub async fn get_raw_notifications(&self, user_id: &str) -> Result<Vec<Document>, StdError> {
+        let notifications = self.0.collection::<Document>(NOTIFICATIONS)
+            .find(doc! { "user": user_id })
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(notifications)
+    }
+
+    pub async fn get_stats(&self) -> Result<DatabaseStats, StdError> {
+        let mut messages = self.0.collection::<Document>(CHATS).aggregate(vec![
+            doc! { "$project": { "count": { "$size": { "$ifNull": ["$messages", []] } } } },
+            doc! { "$group": { "_id": Bson::Null, "total": { "$sum": "$count" } } },
+        ]).await?;
+        let messages = match messages.next().await {
+            Some(total) => match total?.get("total") {
+                Some(Bson::Int32(total)) => *total as i64,
+                Some(Bson::Int64(total)) => *total,
+                _ => 0,
+            },
+            None => 0,
+        };
+
+        Ok(DatabaseStats {
+            users: self.0.collection::<Document>(USERS).count_documents(doc! {}).await?,
+            banned_users: self.0.collection::<Document>(USERS).count_documents(doc! { "banned": true }).await?,
+            chats: self.0.collection::<Document>(CHATS).count_documents(doc! {}).await?,
+            messages,
+            notifications: self.0.collection::<Document>(NOTIFICATIONS).count_documents(doc! {}).await?,
+            quarantined: self.quarantine_collection().count_documents(doc! {}).await?,
+        })
+    }
+
+    pub async fn get_quarantined(&self) -> Result<Vec<Document>, StdError> {
+        let quarantined = self.quarantine_collection()
+            .find(doc! {})
+            .sort(doc! { "quarantined_at": -1 })
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(quarantined)
+    }
+
+    // Puts a fixed version of a quarantined document back in its original collection.
+    pub async fn repair_quarantined(&self, quarantine_id: &str, mut repaired: Document) -> Result<(), StdError> {
+        let entry = self.quarantine_collection()
+            .find_one(doc! { "_id": quarantine_id }).await?
+            .ok_or("Failed to find quarantined document!")?;
+        let collection = entry.get_str("collection")?;
+        let source_id = entry.get("source_id").cloned().ok_or("Quarantined document has no source _id!")?;
+
+        let decodes = match collection {
+            USERS => mongodb::bson::from_document::<DbUser>(repaired.clone()).map(|_| ()),
+            NOTIFICATIONS => mongodb::bson::from_document::<DbNotification>(repaired.clone()).map(|_| ()),
+            CHATS => mongodb::bson::from_document::<DbChat>(repaired.clone()).map(|_| ()),
+            _ => return Err(format!("Unknown quarantined collection {collection}!").into()),
+        };
+        decodes.map_err(|e| format!("Repaired document still fails to decode: {e}"))?;
+
+        repaired.insert("_id", source_id.clone());
+        self.0.collection::<Document>(collection)
+            .replace_one(doc! { "_id": source_id }, repaired)
+            .upsert(true)
+            .await?;
+        self.quarantine_collection().delete_one(doc! { "_id": quarantine_id }).await?;
+
+        Ok(())
     }
 }
 // Private
 impl MongoDB {
-    async fn get_striped_user(&self, document: mongodb::bson::Document) -> Result<User, StdError> {
-        self.get_db_user(document).await
-            .and_then(|db_user| User::from(db_user))
-            .map(|mut user| {
+    // Documents that fail to decode or to convert are moved to the quarantine collection instead of silently dropped.
+    async fn find_one_or_quarantine<D, T, E>(&self, collection: &str, filter: Document, convert: impl Fn(D) -> Result<T, E>) -> Result<Option<T>, StdError>
+    where
+        D: DeserializeOwned,
+        E: std::fmt::Display,
+    {
+        let Some(document) = self.0.collection::<Document>(collection).find_one(filter).await? else { return Ok(None) };
+
+        Ok(self.decode_or_quarantine(collection, vec![Ok(document)], convert).await?.pop())
+    }
+
+    async fn find_or_quarantine<D, T, E>(&self, collection: &str, filter: Document, convert: impl Fn(D) -> Result<T, E>) -> Result<Vec<T>, StdError>
+    where
+        D: DeserializeOwned,
+        E: std::fmt::Display,
+    {
+        let documents = self.0.collection::<Document>(collection).find(filter).await?
+            .collect::<Vec<Result<Document, _>>>().await;
+
+        self.decode_or_quarantine(collection, documents, convert).await
+    }
+
+    // The sort must end with a unique field, _id, for pages not to overlap.
+    async fn find_page_or_quarantine<D, T, E>(&self, collection: &str, filter: Document, sort: Document, paging: Paging, convert: impl Fn(D) -> Result<T, E>) -> Result<Vec<T>, StdError>
+    where
+        D: DeserializeOwned,
+        E: std::fmt::Display,
+    {
+        let documents = self.0.collection::<Document>(collection).find(filter)
+            .sort(sort)
+            .skip(paging.skip)
+            .limit(paging.limit)
+            .await?
+            .collect::<Vec<Result<Document, _>>>().await;
+
+        self.decode_or_quarantine(collection, documents, convert).await
+    }
+
+    async fn aggregate_or_quarantine<D, T, E>(&self, collection: &str, pipeline: Vec<Document>, convert: impl Fn(D) -> Result<T, E>) -> Result<Vec<T>, StdError>
+    where
+        D: DeserializeOwned,
+        E: std::fmt::Display,
+    {
+        let documents = self.0.collection::<Document>(collection).aggregate(pipeline).await?
+            .collect::<Vec<Result<Document, _>>>().await;
+
+        self.decode_or_quarantine(collection, documents, convert).await
+    }
+
+    async fn decode_or_quarantine<D, T, E>(
+        &self,
+        collection: &str,
+        documents: Vec<Result<Document, mongodb::error::Error>>,
+        convert: impl Fn(D) -> Result<T, E>,
+    ) -> Result<Vec<T>, StdError>
+    where
+        D: DeserializeOwned,
+        E: std::fmt::Display,
+    {
+        let mut decoded = Vec::with_capacity(documents.len());
+        for document in documents {
+            let document = document?;
+            let value = mongodb::bson::from_document::<D>(document.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|value| convert(value).map_err(|e| e.to_string()));
+
+            match value {
+                Ok(value) => decoded.push(value),
+                Err(reason) => self.quarantine(collection, document, reason).await,
+            }
+        }
+
+        Ok(decoded)
+    }
+
+    // Moved rather than copied, a document left in place would be quarantined again on every read.
+    // Aggregations may hand over a changed document, the stored one is what gets moved.
+    async fn quarantine(&self, collection: &str, document: Document, reason: String) {
+        let source_id = document.get("_id").cloned().unwrap_or(Bson::Null);
+        let id_string = match &source_id {
+            Bson::String(id) => id.clone(),
+            other => other.to_string(),
+        };
+        warn!("Quarantining document {id_string} from {collection}: {reason}");
+
+        let source = self.0.collection::<Document>(collection);
+        let document = match source.find_one(doc! { "_id": source_id.clone() }).await {
+            Ok(Some(stored)) => stored,
+            Ok(None) => document,
+            Err(e) => {
+                error!("In MongoDB::quarantine: {e}");
+                return;
+            },
+        };
+
+        let quarantine_id = format!("{collection}:{id_string}");
+        if let Err(e) = self.quarantine_collection().replace_one(
+            doc! { "_id": quarantine_id.clone() },
+            doc! {
+                "_id": quarantine_id,
+                "collection": collection,
+                "source_id": source_id.clone(),
+                "document": document,
+                "error": reason,
+                "quarantined_at": DateTime::now(),
+            },
+        )
+        .upsert(true)
+        .await 
+        {
+            error!("In MongoDB::quarantine: {e}");
+            return;
+        }
+
+        if source_id != Bson::Null {
+            if let Err(e) = source.delete_one(doc! { "_id": source_id }).await {
+                error!("In MongoDB::quarantine: {e}");
+            }
+        }
+    }
+
+    // From the cache when possible, the rest in a single query. Users that don't exist are left out.
+    async fn get_striped_users(&self, ids: &[String]) -> Result<Vec<User>, StdError> {
+        let mut found = HashMap::with_capacity(ids.len());
+        let mut missing = Vec::new();
+        {
+            let mut user_cache = self.user_cache();
+            for id in ids {
+                match user_cache.get(id) {
+                    Some(user) => { found.insert(id.clone(), user); },
+                    None => missing.push(id.clone()),
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let users = self.find_or_quarantine(USERS, doc! { "_id": { "$in": missing } }, User::from).await?;
+
+            let mut user_cache = self.user_cache();
+            for mut user in users {
                 user.strip_info();
-                
-                user
-            })
+
+                user_cache.insert(user.uuid().to_string(), user.clone());
+                found.insert(user.uuid().to_string(), user);
+            }
+        }
+
+        Ok(ids.iter().filter_map(|id| found.remove(id)).collect())
+    }
+
+    fn timer(&self, method: &'static str) -> DbTimer {
+        DbTimer {
+            metrics: Arc::clone(&self.2),
+            method,
+            started: Instant::now(),
+        }
+    }
+
+    // Only a cache, a panic while holding it can't leave anything inconsistent.
+    fn user_cache(&self) -> MutexGuard<'_, UserCache> {
+        self.1.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
     async fn get_db_user(&self, document: mongodb::bson::Document) -> Result<DbUser, StdError> {
-        let users = self.user_collection();
-        users.find_one(document).await?.ok_or("Failed to find User!".into())
+        Ok(self.find_one_or_quarantine(USERS, document, Ok::<DbUser, Infallible>).await?.ok_or(ServerError::USER_NOT_FOUND)?)
     }
     
     fn user_collection(&self) -> mongodb::Collection<DbUser> {
-        self.0.collection::<DbUser>("Users")
+        self.0.collection::<DbUser>(USERS)
     }
 
     fn notification_collection(&self) -> mongodb::Collection<DbNotification> {
-        self.0.collection::<DbNotification>("Notifications")
+        self.0.collection::<DbNotification>(NOTIFICATIONS)
     }
     
     fn chat_collection(&self) -> mongodb::Collection<DbChat> {
-        self.0.collection::<DbChat>("Chats")
+        self.0.collection::<DbChat>(CHATS)
+    }
+
+    fn quarantine_collection(&self) -> mongodb::Collection<Document> {
+        self.0.collection::<Document>(QUARANTINE)
     }
-}
\ No newline at end of file
+}
+
+// Sessions are keyed by the SHA-256 of their token.
+fn session_id(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Client input is matched literally, never as a pattern.
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !c.is_alphanumeric() && !c.is_whitespace() && c != '_' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
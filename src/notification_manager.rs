@@ -1,29 +1,86 @@
-use std::collections::HashMap;
+use std::{collections::{HashMap, HashSet}, sync::Arc};
 
-use crate::chat_manager::ChatManager;
+use crate::{chat_manager::ChatManager, metrics::Metrics};
 
-use tokio::sync::mpsc::{Receiver, Sender};
-use yapping_core::{client_server_coms::{Notification, NotificationType}, l3gion_rust::{sllog::{error, info}, UUID}};
+use tokio::sync::{broadcast::error::TryRecvError, mpsc::{error::TrySendError, Receiver, Sender}};
+use tracing::{error, info, warn};
+use yapping_core::{client_server_coms::{Notification, NotificationType}, l3gion_rust::UUID};
+
+// The channels a live connection is reached through.
+#[derive(Clone)]
+pub(crate) struct UserConnection {
+    pub(crate) notification_sender: Sender<Notification>,
+    // Asks the connection to close itself, carrying the reason sent in the close frame.
+    pub(crate) disconnect_sender: Sender<String>,
+}
+impl UserConnection {
+    // Never waits on the client, a slow one would stall every other user.
+    // With its queue full, ephemeral notifications are dropped and anything else disconnects it.
+    // Returns false once the connection should be forgotten.
+    fn deliver(&self, notification: Notification) -> bool {
+        match self.notification_sender.try_send(notification) {
+            Ok(()) => true,
+            Err(TrySendError::Full(notification)) if is_ephemeral(&notification) => {
+                warn!("Dropped a notification for a client that can't keep up");
+                true
+            },
+            Err(TrySendError::Full(_)) => {
+                warn!("Disconnecting a client that can't keep up");
+                self.disconnect("Too slow to keep up!".to_string());
+                false
+            },
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    // Identifies the connection, a user can have a WebSocket and SSE streams open at once.
+    fn is(&self, notification_sender: &Sender<Notification>) -> bool {
+        self.notification_sender.same_channel(notification_sender)
+    }
+
+    fn disconnect(&self, reason: String) {
+        if let Err(e) = self.disconnect_sender.try_send(reason) {
+            error!("In UserConnection::disconnect: {e}");
+        }
+    }
+}
+
+// Only a refresh hint, losing one is harmless.
+// Chat messages are not, a client too slow for them is disconnected and refetches its chats on reconnect.
+fn is_ephemeral(notification: &Notification) -> bool {
+    matches!(notification.notification_type, NotificationType::RESEND_USER(_))
+}
 
 #[allow(non_camel_case_types)]
 pub(crate) enum NotificationManagerMessage {
-    NOTIFY_USER(UUID, Vec<UUID>, Sender<Notification>),
+    // User, its chats, the ids it blocked as stored in the database, the connection.
+    NOTIFY_USER(UUID, Vec<UUID>, HashSet<String>, UserConnection),
     REFRESH_USER(UUID),
-    USER_OFFLINE,
+    // The user's new block list, after it blocked or unblocked someone.
+    BLOCKED_USERS(UUID, HashSet<String>),
+    // The notification sender of the connection going away, the user's other connections stay.
+    USER_OFFLINE(Sender<Notification>),
     CLIENT_MESSAGE(Notification),
+    // User id as stored in the database, reason.
+    DISCONNECT_USER(String, String),
 }
 
 pub(crate) struct NotificationManager {
     sender: Sender<(UUID, NotificationManagerMessage)>,
     receiver: Receiver<(UUID, NotificationManagerMessage)>,
     
-    users: HashMap<UUID, Sender<Notification>>,
-    chat_users: HashMap<UUID, Vec<tokio::sync::broadcast::Receiver<Notification>>>,
+    // Every live connection of each user.
+    users: HashMap<UUID, Vec<UserConnection>>,
+    // One set per user, whatever its number of connections, notify fans out to them.
+    chat_users: HashMap<UUID, Vec<tokio::sync::broadcast::Receiver<(UUID, Notification)>>>,
+    // Ids blocked by each user, messages they post in shared chats aren't delivered to it.
+    blocked_users: HashMap<UUID, HashSet<String>>,
 
     chat_manager: ChatManager,
+    metrics: Arc<Metrics>,
 }
 impl NotificationManager {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
         let (sender, receiver) = tokio::sync::mpsc::channel(100);
 
         Self {
@@ -31,7 +88,9 @@ impl NotificationManager {
             receiver,
             users: HashMap::default(),
             chat_users: HashMap::default(),
+            blocked_users: HashMap::default(),
             chat_manager: ChatManager::default(),
+            metrics,
         }
     }
     
@@ -43,82 +102,136 @@ impl NotificationManager {
         tokio::spawn(async move {
             info!("UserManager task spawned!");
 
-            loop {
-                while let Ok((sender_uuid, msg)) = self.receiver.try_recv() {
-                    match msg {
-                        NotificationManagerMessage::NOTIFY_USER(user_uuid, user_chats, user_notification) => {
-                            self.users.insert(user_uuid, user_notification);
-                            
-                            for chat in user_chats {
-                                self.chat_manager.new_chat(chat);
-                                if let Some(receiver) = self.chat_manager.subscribe(chat) {
-                                    self.chat_users.entry(user_uuid).or_insert(vec![])
-                                        .push(receiver);
-                                }
-                            }
-                        },
-                        NotificationManagerMessage::REFRESH_USER(user_uuid) => {
-                            if let Some(user_sender) = self.users.get(&user_uuid) {
-                                if let Err(e) = user_sender.send(Notification::new(NotificationType::RESEND_USER(UUID::default()))).await {
-                                    error!("In NotificationManager::start_recv::REFRESH_USER: {e}");
-                                }
-                            }
-                        }
-                        NotificationManagerMessage::USER_OFFLINE => {
-                            self.users.remove(&sender_uuid);
-                            self.chat_users.remove(&sender_uuid);
-                        }
+            // The sender kept in self means this never ends.
+            while let Some((sender_uuid, msg)) = self.receiver.recv().await {
+                self.handle(sender_uuid, msg);
+                self.deliver_chat_notifications();
+            }
+        });
+    }
+}
+// Private
+impl NotificationManager {
+    fn handle(&mut self, sender_uuid: UUID, msg: NotificationManagerMessage) {
+        match msg {
+            NotificationManagerMessage::NOTIFY_USER(user_uuid, user_chats, blocked_users, user_connection) => {
+                let connections = self.users.entry(user_uuid).or_default();
+                connections.retain(|c| !c.is(&user_connection.notification_sender));
+                connections.push(user_connection);
+                
+                let mut receivers = vec![];
+                for chat in user_chats {
+                    self.chat_manager.new_chat(chat);
+                    if let Some(receiver) = self.chat_manager.subscribe(chat) {
+                        receivers.push(receiver);
+                    }
+                }
+                self.chat_users.insert(user_uuid, receivers);
+                self.blocked_users.insert(user_uuid, blocked_users);
+            },
+            NotificationManagerMessage::REFRESH_USER(user_uuid) => {
+                self.notify(user_uuid, Notification::new(NotificationType::RESEND_USER(UUID::default())));
+            }
+            NotificationManagerMessage::BLOCKED_USERS(user_uuid, blocked_users) => {
+                if self.users.contains_key(&user_uuid) {
+                    self.blocked_users.insert(user_uuid, blocked_users);
+                }
+            }
+            NotificationManagerMessage::USER_OFFLINE(notification_sender) => {
+                if let Some(connections) = self.users.get_mut(&sender_uuid) {
+                    connections.retain(|c| !c.is(&notification_sender));
+                    if connections.is_empty() {
+                        self.forget(sender_uuid);
+                    }
+                }
+            }
+            NotificationManagerMessage::DISCONNECT_USER(user_id, reason) => {
+                let user_uuid = self.users.keys().find(|u| u.to_string() == user_id).copied();
 
-                        NotificationManagerMessage::CLIENT_MESSAGE(notification) => {
-                            match notification.notification_type().clone() {
-                                NotificationType::NEW_CHAT(chat) => {
-                                    self.chat_manager.new_chat(chat.uuid());
-                                    
-                                    for u in chat.users() {
-                                        if let Some(user_sender) = self.users.get(u) {
-                                            if let Some(receiver) = self.chat_manager.subscribe(chat.uuid()) {
-                                                self.chat_users.entry(*u).or_insert(vec![])
-                                                    .push(receiver);
-                                                
-                                                if let Err(e) = user_sender.send(Notification::new(NotificationType::NEW_CHAT(chat.clone()))).await {
-                                                    error!("In NotificationManager::start_recv::NEW_CHAT: {e}")
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                                NotificationType::NEW_MESSAGE(chat_uuid, _) => self.chat_manager.post(chat_uuid, notification),
-                                NotificationType::FRIEND_REQUEST(_, receiver) 
-                                | NotificationType::FRIEND_ACCEPTED(_, receiver)=> {
-                                    if let Some(receiving_user_sender) = self.users.get(&receiver) {
-                                        if let Err(e) = receiving_user_sender.send(notification).await {
-                                            error!("In NotificationManagerMessage::start_recv::FRIEND_ACCEPTED: {e}");
-                                        }
-                                    }
-                                },
-                                _ => (),
-                        }
-                    }};
+                if let Some(user_uuid) = user_uuid {
+                    info!("Disconnecting user {user_id}: {reason}");
+                    for user_connection in self.users.remove(&user_uuid).unwrap_or_default() {
+                        user_connection.disconnect(reason.clone());
+                    }
+                    self.forget(user_uuid);
                 }
-            
-                // Bad? Yep bad. Slow? Very.
-                for (chat_user, receivers) in &mut self.chat_users {
-                    if let Some(sender) = self.users.get_mut(chat_user) {
-                        for receiver in receivers {
-                            while let Ok(notification) = receiver.try_recv() {
-                                match notification.notification_type {
-                                    NotificationType::NEW_MESSAGE(chat_uuid, msg) => if let Err(e) = sender
-                                       .send(Notification::new(NotificationType::NEW_MESSAGE(chat_uuid, msg))).await 
-                                    {
-                                        error!("In NotificationManager::start_recv: {e}");
-                                    }
-                                    _ => error!("In NotificationManager::ChatManager update: Wrong NotificationType!"),
-                                }
+            }
+
+            NotificationManagerMessage::CLIENT_MESSAGE(notification) => {
+                match notification.notification_type().clone() {
+                    NotificationType::NEW_CHAT(chat) => {
+                        self.chat_manager.new_chat(chat.uuid());
+                        
+                        for u in chat.users() {
+                            if !self.users.contains_key(u) {
+                                continue;
+                            }
+
+                            if let Some(receiver) = self.chat_manager.subscribe(chat.uuid()) {
+                                self.chat_users.entry(*u).or_insert(vec![])
+                                    .push(receiver);
+                                
+                                self.notify(*u, Notification::new(NotificationType::NEW_CHAT(chat.clone())));
                             }
                         }
                     }
+                    NotificationType::NEW_MESSAGE(chat_uuid, _) => self.chat_manager.post(chat_uuid, sender_uuid, notification),
+                    NotificationType::FRIEND_REQUEST(_, receiver) 
+                    | NotificationType::FRIEND_ACCEPTED(_, receiver) => self.notify(receiver, notification),
+                    _ => (),
                 }
             }
-        });
+        }
+    }
+
+    // Chat broadcasts are only posted by handle, on this task, so draining them after each message misses nothing.
+    fn deliver_chat_notifications(&mut self) {
+        let mut chat_notifications = vec![];
+        for (chat_user, receivers) in &mut self.chat_users {
+            for receiver in receivers {
+                loop {
+                    let (poster_uuid, notification) = match receiver.try_recv() {
+                        Ok(posted) => posted,
+                        // The ChatManager channel overwrote what this user hadn't read yet, the rest is still there.
+                        Err(TryRecvError::Lagged(skipped)) => {
+                            warn!("A chat receiver skipped {skipped} notifications");
+                            self.metrics.broadcast_lagged(skipped);
+                            continue;
+                        },
+                        Err(_) => break,
+                    };
+                    // Messages from blocked users stay in shared chats but are hidden from the blocker.
+                    let blocked = self.blocked_users.get(chat_user).is_some_and(|blocked| blocked.contains(&poster_uuid.to_string()));
+                    if blocked {
+                        continue;
+                    }
+
+                    match notification.notification_type {
+                        NotificationType::NEW_MESSAGE(chat_uuid, msg) => chat_notifications.push(
+                            (*chat_user, Notification::new(NotificationType::NEW_MESSAGE(chat_uuid, msg)))
+                        ),
+                        _ => error!("In NotificationManager::ChatManager update: Wrong NotificationType!"),
+                    }
+                }
+            }
+        }
+        for (chat_user, notification) in chat_notifications {
+            self.notify(chat_user, notification);
+        }
+    }
+
+    fn notify(&mut self, user_uuid: UUID, notification: Notification) {
+        let Some(connections) = self.users.get_mut(&user_uuid) else { return };
+
+        connections.retain(|c| c.deliver(notification.clone()));
+        if connections.is_empty() {
+            self.forget(user_uuid);
+        }
+    }
+
+    fn forget(&mut self, user_uuid: UUID) {
+        self.users.remove(&user_uuid);
+        self.chat_users.remove(&user_uuid);
+        self.blocked_users.remove(&user_uuid);
     }
 }
\ No newline at end of file
@@ -0,0 +1,210 @@
+use std::collections::HashSet;
+
+use yapping_core::l3gion_rust::UUID;
+
+// Every permission check made by the handlers lives here.
+// The checks only take plain data, the callers fetch whatever they need from the database first.
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Denial {
+    NOT_LOGGED_IN,
+    // The message claims to be sent by someone other than the logged in user.
+    NOT_THE_SENDER,
+    NOT_A_CHAT_MEMBER,
+    CREATOR_NOT_IN_CHAT,
+    TOO_MANY_CHAT_MEMBERS,
+    CHAT_MEMBER_NOT_A_FRIEND,
+    NOT_FRIENDS,
+    TARGETS_SELF,
+    // Deliberately vague so senders can't tell they were blocked.
+    FRIEND_REQUEST_REFUSED,
+    NO_PENDING_FRIEND_REQUEST,
+}
+impl Denial {
+    pub(crate) fn reason(&self) -> &'static str {
+        match self {
+            Denial::NOT_LOGGED_IN => "User is not logged in!",
+            Denial::NOT_THE_SENDER => "User can't act on behalf of another user!",
+            Denial::NOT_A_CHAT_MEMBER => "User is not a member of the chat!",
+            Denial::CREATOR_NOT_IN_CHAT => "The creator must be a member of the chat!",
+            Denial::TOO_MANY_CHAT_MEMBERS => "The chat has too many members!",
+            Denial::CHAT_MEMBER_NOT_A_FRIEND => "Every member of the chat must be a friend of the creator!",
+            Denial::NOT_FRIENDS => "Users are not friends!",
+            Denial::TARGETS_SELF => "User can't target themselves!",
+            Denial::FRIEND_REQUEST_REFUSED => "Failed to send friend request!",
+            Denial::NO_PENDING_FRIEND_REQUEST => "Failed to find friend request!",
+        }
+    }
+}
+impl std::fmt::Display for Denial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.reason())
+    }
+}
+impl std::error::Error for Denial {}
+
+pub(crate) fn logged_in(user: UUID) -> Result<(), Denial> {
+    if !user.is_valid() {
+        return Err(Denial::NOT_LOGGED_IN);
+    }
+
+    Ok(())
+}
+
+// For messages that carry their sender, which must be the logged in user.
+pub(crate) fn acting_as(user: UUID, sender: UUID) -> Result<(), Denial> {
+    logged_in(user)?;
+    if user != sender {
+        return Err(Denial::NOT_THE_SENDER);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn chat_member(user: UUID, members: &[UUID]) -> Result<(), Denial> {
+    logged_in(user)?;
+    if !members.contains(&user) {
+        return Err(Denial::NOT_A_CHAT_MEMBER);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn create_chat(creator: UUID, members: &[UUID], creator_friends: &HashSet<UUID>, max_members: usize) -> Result<(), Denial> {
+    logged_in(creator)?;
+    if !members.contains(&creator) {
+        return Err(Denial::CREATOR_NOT_IN_CHAT);
+    }
+    if members.len() > max_members {
+        return Err(Denial::TOO_MANY_CHAT_MEMBERS);
+    }
+    if members.iter().any(|m| *m != creator && !creator_friends.contains(m)) {
+        return Err(Denial::CHAT_MEMBER_NOT_A_FRIEND);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn send_friend_request(user: UUID, sender: UUID, receiver: UUID, blocked_by_receiver: bool) -> Result<(), Denial> {
+    acting_as(user, sender)?;
+    if sender == receiver {
+        return Err(Denial::TARGETS_SELF);
+    }
+    if blocked_by_receiver {
+        return Err(Denial::FRIEND_REQUEST_REFUSED);
+    }
+
+    Ok(())
+}
+
+// Accepting, declining or canceling, all need the request to still be pending.
+pub(crate) fn answer_friend_request(user: UUID, request_pending: bool) -> Result<(), Denial> {
+    logged_in(user)?;
+    if !request_pending {
+        return Err(Denial::NO_PENDING_FRIEND_REQUEST);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn remove_friend(user: UUID, friends: &HashSet<UUID>, friend: UUID) -> Result<(), Denial> {
+    logged_in(user)?;
+    if !friends.contains(&friend) {
+        return Err(Denial::NOT_FRIENDS);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn block_user(user: UUID, target: UUID) -> Result<(), Denial> {
+    logged_in(user)?;
+    if user == target {
+        return Err(Denial::TARGETS_SELF);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users<const N: usize>() -> [UUID; N] {
+        std::array::from_fn(|_| UUID::generate())
+    }
+
+    #[test]
+    fn logged_out_users_are_denied() {
+        let [user] = users();
+
+        assert_eq!(logged_in(UUID::default()), Err(Denial::NOT_LOGGED_IN));
+        assert_eq!(acting_as(UUID::default(), UUID::default()), Err(Denial::NOT_LOGGED_IN));
+        assert_eq!(chat_member(UUID::default(), &[UUID::default()]), Err(Denial::NOT_LOGGED_IN));
+        assert_eq!(block_user(UUID::default(), user), Err(Denial::NOT_LOGGED_IN));
+        assert_eq!(logged_in(user), Ok(()));
+    }
+
+    #[test]
+    fn acting_as_someone_else_is_denied() {
+        let [user, other] = users();
+
+        assert_eq!(acting_as(user, other), Err(Denial::NOT_THE_SENDER));
+        assert_eq!(acting_as(user, user), Ok(()));
+    }
+
+    #[test]
+    fn only_members_of_a_chat_reach_it() {
+        let [user, member] = users();
+
+        assert_eq!(chat_member(user, &[member]), Err(Denial::NOT_A_CHAT_MEMBER));
+        assert_eq!(chat_member(user, &[user, member]), Ok(()));
+    }
+
+    #[test]
+    fn chats_are_created_with_the_creator_and_its_friends() {
+        let [creator, friend, stranger] = users();
+        let friends = HashSet::from([friend]);
+
+        assert_eq!(create_chat(creator, &[friend], &friends, 8), Err(Denial::CREATOR_NOT_IN_CHAT));
+        assert_eq!(create_chat(creator, &[creator, friend], &friends, 1), Err(Denial::TOO_MANY_CHAT_MEMBERS));
+        assert_eq!(create_chat(creator, &[creator, stranger], &friends, 8), Err(Denial::CHAT_MEMBER_NOT_A_FRIEND));
+        assert_eq!(create_chat(creator, &[creator, friend], &friends, 8), Ok(()));
+    }
+
+    #[test]
+    fn friend_requests_go_to_someone_else_who_didnt_block_the_sender() {
+        let [sender, receiver] = users();
+
+        assert_eq!(send_friend_request(receiver, sender, receiver, false), Err(Denial::NOT_THE_SENDER));
+        assert_eq!(send_friend_request(sender, sender, sender, false), Err(Denial::TARGETS_SELF));
+        assert_eq!(send_friend_request(sender, sender, receiver, true), Err(Denial::FRIEND_REQUEST_REFUSED));
+        assert_eq!(send_friend_request(sender, sender, receiver, false), Ok(()));
+    }
+
+    #[test]
+    fn only_pending_friend_requests_are_answered() {
+        let [user] = users();
+
+        assert_eq!(answer_friend_request(UUID::default(), true), Err(Denial::NOT_LOGGED_IN));
+        assert_eq!(answer_friend_request(user, false), Err(Denial::NO_PENDING_FRIEND_REQUEST));
+        assert_eq!(answer_friend_request(user, true), Ok(()));
+    }
+
+    #[test]
+    fn only_friends_are_removed() {
+        let [user, friend, stranger] = users();
+        let friends = HashSet::from([friend]);
+
+        assert_eq!(remove_friend(user, &friends, stranger), Err(Denial::NOT_FRIENDS));
+        assert_eq!(remove_friend(user, &friends, friend), Ok(()));
+    }
+
+    #[test]
+    fn users_cant_block_themselves() {
+        let [user, other] = users();
+
+        assert_eq!(block_user(user, user), Err(Denial::TARGETS_SELF));
+        assert_eq!(block_user(user, other), Ok(()));
+    }
+}
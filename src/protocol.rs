@@ -0,0 +1,163 @@
+use std::collections::HashSet;
+
+// Bumped whenever a change to the yapping_core messages would break older clients.
+pub(crate) const PROTOCOL_VERSION: u32 = 2;
+// Clients that start without a HELLO predate the handshake.
+pub(crate) const LEGACY_PROTOCOL_VERSION: u32 = 1;
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Close code sent to clients below MIN_PROTOCOL_VERSION, mirrors HTTP 426.
+pub(crate) const UPGRADE_REQUIRED: u16 = 4426;
+
+// Zstd compression of large binary frames, see Codec::encode.
+pub(crate) const ZSTD: &str = "zstd";
+
+// Optional features a client can ask for in its HELLO, on top of what its version gives.
+const SERVER_CAPABILITIES: &[&str] = &[ZSTD];
+
+// First frame of a connection, as text: "HELLO <version> <capability,capability,...>".
+// The server answers with the same frame, holding the negotiated version and the capabilities it accepted.
+#[derive(Debug, Clone)]
+pub(crate) struct Hello {
+    pub(crate) version: u32,
+    pub(crate) capabilities: Vec<String>,
+}
+impl Hello {
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let mut parts = text.split_whitespace();
+        if parts.next() != Some("HELLO") {
+            return Err("Expected HELLO".to_string());
+        }
+
+        let version = parts.next()
+            .ok_or("Missing protocol version")?
+            .parse()
+            .map_err(|_| "Invalid protocol version")?;
+        let capabilities = parts.next()
+            .map(|caps| caps.split(',').filter(|c| !c.is_empty()).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        Ok(Self { version, capabilities })
+    }
+
+    pub(crate) fn to_text(&self) -> String {
+        format!("HELLO {} {}", self.version, self.capabilities.join(","))
+    }
+
+    pub(crate) fn is_hello(text: &str) -> bool {
+        text.split_whitespace().next() == Some("HELLO")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Protocol {
+    version: u32,
+    capabilities: HashSet<String>,
+}
+impl Protocol {
+    pub(crate) fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: HashSet::default(),
+        }
+    }
+
+    // Newer clients are served at our version, older ones at theirs while still supported.
+    // Fails with the minimum version the client must upgrade to.
+    pub(crate) fn negotiate(hello: &Hello) -> Result<Self, u32> {
+        if hello.version < MIN_PROTOCOL_VERSION {
+            return Err(MIN_PROTOCOL_VERSION);
+        }
+
+        Ok(Self {
+            version: hello.version.min(PROTOCOL_VERSION),
+            capabilities: hello.capabilities.iter()
+                .filter(|c| SERVER_CAPABILITIES.contains(&c.as_str()))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub(crate) fn hello(&self) -> Hello {
+        let mut capabilities = self.capabilities.iter().cloned().collect::<Vec<_>>();
+        capabilities.sort();
+
+        Hello {
+            version: self.version,
+            capabilities,
+        }
+    }
+
+    pub(crate) fn version(&self) -> u32 {
+        self.version
+    }
+
+    pub(crate) fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hellos_round_trip() {
+        let hello = Hello::parse("HELLO 2 zstd,other").unwrap();
+        assert_eq!(hello.version, 2);
+        assert_eq!(hello.capabilities, ["zstd", "other"]);
+        assert_eq!(hello.to_text(), "HELLO 2 zstd,other");
+
+        let hello = Hello::parse("HELLO 1").unwrap();
+        assert!(hello.capabilities.is_empty());
+    }
+
+    #[test]
+    fn invalid_hellos_are_rejected() {
+        assert!(Hello::parse("").is_err());
+        assert!(Hello::parse("HI 2").is_err());
+        assert!(Hello::parse("HELLO").is_err());
+        assert!(Hello::parse("HELLO two").is_err());
+
+        assert!(Hello::is_hello("HELLO 2"));
+        assert!(!Hello::is_hello("{\"uuid\": 0}"));
+    }
+
+    #[test]
+    fn newer_clients_get_our_version() {
+        let protocol = Protocol::negotiate(&Hello { version: PROTOCOL_VERSION + 1, capabilities: vec![] }).unwrap();
+
+        assert_eq!(protocol.version(), PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn older_clients_keep_theirs() {
+        let protocol = Protocol::negotiate(&Hello { version: 1, capabilities: vec![] }).unwrap();
+
+        assert_eq!(protocol.version(), 1);
+    }
+
+    #[test]
+    fn unsupported_versions_must_upgrade() {
+        let hello = Hello { version: MIN_PROTOCOL_VERSION - 1, capabilities: vec![] };
+        assert_eq!(Protocol::negotiate(&hello).unwrap_err(), MIN_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn only_known_capabilities_are_accepted() {
+        let hello = Hello { version: PROTOCOL_VERSION, capabilities: vec!["brotli".to_string(), ZSTD.to_string()] };
+        let protocol = Protocol::negotiate(&hello).unwrap();
+
+        assert!(protocol.has_capability(ZSTD));
+        assert!(!protocol.has_capability("brotli"));
+        assert_eq!(protocol.hello().to_text(), format!("HELLO {PROTOCOL_VERSION} {ZSTD}"));
+    }
+
+    #[test]
+    fn legacy_clients_get_version_one_without_capabilities() {
+        let protocol = Protocol::legacy();
+
+        assert_eq!(protocol.version(), LEGACY_PROTOCOL_VERSION);
+        assert!(!protocol.has_capability(ZSTD));
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::HashMap, net::IpAddr, str::FromStr, time::{Duration, Instant}};
+
+use yapping_core::{client_server_coms::ServerMessageContent, l3gion_rust::UUID};
+
+// Buckets that refilled completely are dropped every this many checks.
+const PRUNE_EVERY: u32 = 1024;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum MessageKind {
+    SESSION,
+    QUERY,
+    NOTIFICATION,
+    MODIFICATION,
+}
+impl MessageKind {
+    // Responses are acknowledgements and are never limited.
+    pub(crate) fn of(content: &ServerMessageContent) -> Option<Self> {
+        match content {
+            ServerMessageContent::SESSION(_) => Some(MessageKind::SESSION),
+            ServerMessageContent::QUERY(_) => Some(MessageKind::QUERY),
+            ServerMessageContent::NOTIFICATION(_) => Some(MessageKind::NOTIFICATION),
+            ServerMessageContent::MODIFICATION(_) => Some(MessageKind::MODIFICATION),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            MessageKind::SESSION => "session",
+            MessageKind::QUERY => "query",
+            MessageKind::NOTIFICATION => "notification",
+            MessageKind::MODIFICATION => "modification",
+        }
+    }
+}
+
+// Written as "<burst>/<per minute>" in the configuration.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimit {
+    pub(crate) burst: u32,
+    pub(crate) per_minute: u32,
+}
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (burst, per_minute) = s.split_once('/').ok_or("Expected <burst>/<per minute>")?;
+
+        Ok(Self {
+            burst: burst.trim().parse().map_err(|_| "Invalid burst")?,
+            per_minute: per_minute.trim().parse().map_err(|_| "Invalid rate")?,
+        })
+    }
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum BucketKey {
+    USER(UUID, MessageKind),
+    ADDRESS(IpAddr, MessageKind),
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+impl TokenBucket {
+    fn refill(&mut self, limit: RateLimit) {
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * limit.per_minute as f64 / 60.0;
+
+        self.tokens = (self.tokens + refilled).min(limit.burst as f64);
+        self.last_refill = now;
+    }
+
+    fn retry_after(&self, limit: RateLimit) -> Duration {
+        if limit.per_minute == 0 {
+            return Duration::from_secs(60);
+        }
+
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / limit.per_minute as f64)
+    }
+}
+
+// Shared by every connection, so limits keyed by address hold across reconnects.
+pub(crate) struct RateLimiter {
+    limits: HashMap<MessageKind, RateLimit>,
+    buckets: HashMap<BucketKey, TokenBucket>,
+    checks: u32,
+}
+impl RateLimiter {
+    pub(crate) fn new(limits: HashMap<MessageKind, RateLimit>) -> Self {
+        Self {
+            limits,
+            buckets: HashMap::default(),
+            checks: 0,
+        }
+    }
+
+    // Takes a token from the address bucket and, once logged in, from the user bucket.
+    // Fails with how long to wait before the message would be allowed.
+    pub(crate) fn check(&mut self, user: UUID, address: IpAddr, kind: MessageKind) -> Result<(), Duration> {
+        let Some(limit) = self.limits.get(&kind).copied() else { return Ok(()) };
+
+        self.checks += 1;
+        if self.checks % PRUNE_EVERY == 0 {
+            self.prune();
+        }
+
+        let mut keys = vec![BucketKey::ADDRESS(address, kind)];
+        if user.is_valid() {
+            keys.push(BucketKey::USER(user, kind));
+        }
+
+        let mut retry_after = None;
+        for key in &keys {
+            let bucket = self.buckets.entry(*key).or_insert(TokenBucket {
+                tokens: limit.burst as f64,
+                last_refill: Instant::now(),
+            });
+            bucket.refill(limit);
+
+            if bucket.tokens < 1.0 {
+                retry_after = retry_after.max(Some(bucket.retry_after(limit)));
+            }
+        }
+
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        for key in &keys {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+}
+// Private
+impl RateLimiter {
+    fn prune(&mut self) {
+        let limits = &self.limits;
+
+        self.buckets.retain(|key, bucket| {
+            let kind = match key {
+                BucketKey::USER(_, kind) | BucketKey::ADDRESS(_, kind) => kind,
+            };
+
+            match limits.get(kind) {
+                Some(limit) => {
+                    bucket.refill(*limit);
+                    bucket.tokens < limit.burst as f64
+                }
+                None => false,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+        RateLimiter::new(HashMap::from([(MessageKind::QUERY, RateLimit { burst, per_minute })]))
+    }
+
+    // As if the bucket was last refilled `elapsed` ago.
+    fn wait(limiter: &mut RateLimiter, elapsed: Duration) {
+        for bucket in limiter.buckets.values_mut() {
+            bucket.last_refill -= elapsed;
+        }
+    }
+
+    #[test]
+    fn bursts_are_allowed_then_limited() {
+        let mut limiter = limiter(3, 60);
+        let user = UUID::generate();
+
+        for _ in 0..3 {
+            assert_eq!(limiter.check(user, ADDRESS, MessageKind::QUERY), Ok(()));
+        }
+        let retry_after = limiter.check(user, ADDRESS, MessageKind::QUERY).unwrap_err();
+        assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut limiter = limiter(2, 60);
+        let user = UUID::generate();
+
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_err());
+
+        // One token a second.
+        wait(&mut limiter, Duration::from_secs(1));
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_err());
+    }
+
+    #[test]
+    fn refills_stop_at_the_burst() {
+        let mut limiter = limiter(2, 60);
+        let user = UUID::generate();
+
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        wait(&mut limiter, Duration::from_secs(60));
+
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(user, ADDRESS, MessageKind::QUERY).is_err());
+    }
+
+    #[test]
+    fn the_address_is_limited_across_users() {
+        let mut limiter = limiter(1, 60);
+
+        assert!(limiter.check(UUID::generate(), ADDRESS, MessageKind::QUERY).is_ok());
+        assert!(limiter.check(UUID::generate(), ADDRESS, MessageKind::QUERY).is_err());
+        assert!(limiter.check(UUID::default(), ADDRESS, MessageKind::QUERY).is_err());
+    }
+
+    #[test]
+    fn unlimited_kinds_are_always_allowed() {
+        let mut limiter = limiter(0, 0);
+
+        for _ in 0..10 {
+            assert!(limiter.check(UUID::generate(), ADDRESS, MessageKind::SESSION).is_ok());
+        }
+        assert_eq!(limiter.check(UUID::generate(), ADDRESS, MessageKind::QUERY), Err(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn rate_limits_are_parsed() {
+        let limit = "5 / 30".parse::<RateLimit>().unwrap();
+        assert_eq!((limit.burst, limit.per_minute), (5, 30));
+
+        assert!("5".parse::<RateLimit>().is_err());
+        assert!("a/30".parse::<RateLimit>().is_err());
+    }
+}
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use tracing::{error, warn};
+use yapping_core::l3gion_rust::StdError;
+
+use crate::policy::Denial;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCategory {
+    AUTH,
+    VALIDATION,
+    NOT_FOUND,
+    CONFLICT,
+    RATE_LIMITED,
+    INTERNAL,
+}
+
+// Errors sent to clients inside Response::Err as the same JSON object the HTTP API answers with:
+// {"code": <code>, "message": <message>}, plus "retry_after_ms" when rate limited.
+// Codes are stable, never reuse or renumber one. Anything internal is only logged.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone)]
+pub(crate) enum ServerError {
+    INVALID_CREDENTIALS,
+    ACCOUNT_BANNED(String),
+    // Until, reason.
+    ACCOUNT_SUSPENDED(String, String),
+    // Missing, unknown or expired bearer token.
+    INVALID_SESSION,
+    DENIED(Denial),
+
+    MISSING_FIELDS,
+    UNSUPPORTED_REQUEST,
+    // The minimum length.
+    QUERY_TOO_SHORT(usize),
+
+    CHAT_NOT_FOUND,
+    USER_NOT_FOUND,
+
+    USER_ALREADY_EXISTS,
+    CHAT_ALREADY_EXISTS,
+
+    RATE_LIMITED(Duration),
+
+    INTERNAL(String),
+}
+impl ServerError {
+    pub(crate) fn code(&self) -> u16 {
+        match self {
+            ServerError::INVALID_CREDENTIALS => 1000,
+            ServerError::ACCOUNT_BANNED(_) => 1001,
+            ServerError::ACCOUNT_SUSPENDED(_, _) => 1002,
+            ServerError::INVALID_SESSION => 1003,
+            ServerError::DENIED(denial) => match denial {
+                Denial::NOT_LOGGED_IN => 1100,
+                Denial::NOT_THE_SENDER => 1101,
+                Denial::NOT_A_CHAT_MEMBER => 1102,
+                Denial::CREATOR_NOT_IN_CHAT => 1103,
+                Denial::TOO_MANY_CHAT_MEMBERS => 1104,
+                Denial::CHAT_MEMBER_NOT_A_FRIEND => 1105,
+                Denial::NOT_FRIENDS => 1106,
+                Denial::TARGETS_SELF => 1107,
+                Denial::FRIEND_REQUEST_REFUSED => 1108,
+                Denial::NO_PENDING_FRIEND_REQUEST => 1109,
+            },
+
+            ServerError::MISSING_FIELDS => 2000,
+            ServerError::UNSUPPORTED_REQUEST => 2001,
+            ServerError::QUERY_TOO_SHORT(_) => 2002,
+
+            ServerError::CHAT_NOT_FOUND => 3000,
+            ServerError::USER_NOT_FOUND => 3001,
+
+            ServerError::USER_ALREADY_EXISTS => 4000,
+            ServerError::CHAT_ALREADY_EXISTS => 4001,
+
+            ServerError::RATE_LIMITED(_) => 5000,
+
+            ServerError::INTERNAL(_) => 9000,
+        }
+    }
+
+    pub(crate) fn category(&self) -> ErrorCategory {
+        match self.code() {
+            1000..=1999 => ErrorCategory::AUTH,
+            2000..=2999 => ErrorCategory::VALIDATION,
+            3000..=3999 => ErrorCategory::NOT_FOUND,
+            4000..=4999 => ErrorCategory::CONFLICT,
+            5000..=5999 => ErrorCategory::RATE_LIMITED,
+            _ => ErrorCategory::INTERNAL,
+        }
+    }
+
+    pub(crate) fn client_message(&self) -> String {
+        match self {
+            ServerError::INVALID_CREDENTIALS => "Invalid email or password!".to_string(),
+            ServerError::ACCOUNT_BANNED(reason) => format!("Account is banned: {reason}"),
+            ServerError::ACCOUNT_SUSPENDED(until, reason) => format!("Account is suspended until {until}: {reason}"),
+            ServerError::INVALID_SESSION => "Invalid or expired session!".to_string(),
+            ServerError::DENIED(denial) => denial.reason().to_string(),
+
+            ServerError::MISSING_FIELDS => "Please fill all the fields!".to_string(),
+            ServerError::UNSUPPORTED_REQUEST => "Request is not supported!".to_string(),
+            ServerError::QUERY_TOO_SHORT(min_length) => format!("Search must be at least {min_length} characters long!"),
+
+            ServerError::CHAT_NOT_FOUND => "Chat not found!".to_string(),
+            ServerError::USER_NOT_FOUND => "User not found!".to_string(),
+
+            ServerError::USER_ALREADY_EXISTS => "User already exists!".to_string(),
+            ServerError::CHAT_ALREADY_EXISTS => "Chat already exists!".to_string(),
+
+            ServerError::RATE_LIMITED(_) => "Too many requests!".to_string(),
+
+            ServerError::INTERNAL(_) => "Internal server error!".to_string(),
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut body = serde_json::json!({
+            "code": self.code(),
+            "message": self.client_message(),
+        });
+        if let ServerError::RATE_LIMITED(retry_after) = self {
+            body["retry_after_ms"] = serde_json::json!(retry_after.as_millis() as u64);
+        }
+
+        body
+    }
+
+    pub(crate) fn to_client_string(&self) -> String {
+        self.to_json().to_string()
+    }
+
+    // The full details, for the server logs only.
+    pub(crate) fn log(&self) {
+        match (self, self.category()) {
+            (ServerError::INTERNAL(details), _) => error!("Internal error {}: {details}", self.code()),
+            (_, category) => warn!("Responding with {:?} error {}: {}", category, self.code(), self.client_message()),
+        }
+    }
+}
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::INTERNAL(details) => write!(f, "{}: {details}", self.code()),
+            _ => write!(f, "{}: {}", self.code(), self.client_message()),
+        }
+    }
+}
+impl std::error::Error for ServerError {}
+
+impl From<Denial> for ServerError {
+    fn from(denial: Denial) -> Self {
+        ServerError::DENIED(denial)
+    }
+}
+impl From<StdError> for ServerError {
+    fn from(e: StdError) -> Self {
+        if let Some(e) = e.downcast_ref::<ServerError>() {
+            return e.clone();
+        }
+        if let Some(denial) = e.downcast_ref::<Denial>() {
+            return ServerError::DENIED(*denial);
+        }
+
+        ServerError::INTERNAL(e.to_string())
+    }
+}
+impl From<mongodb::error::Error> for ServerError {
+    fn from(e: mongodb::error::Error) -> Self {
+        ServerError::INTERNAL(e.to_string())
+    }
+}
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ServerError {
+    fn from(e: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        ServerError::INTERNAL(e.to_string())
+    }
+}
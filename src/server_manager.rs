@@ -1,41 +1,156 @@
-use std::sync::Arc;
+use std::{collections::HashMap, future::Future, net::IpAddr, sync::{Arc, Mutex as StdMutex}, time::Duration};
 
 use futures::StreamExt;
 use tokio::{net::TcpListener, sync::{mpsc::Sender, Mutex}};
-use tokio_tungstenite::accept_async;
-use yapping_core::l3gion_rust::{sllog::{error, info, warn}, StdError, UUID};
-use tokio_tungstenite::tungstenite::Message as TkMessage;
+use tokio_tungstenite::{accept_hdr_async_with_config, tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue}, protocol::WebSocketConfig}};
+use tracing::{error, field, info, info_span, warn, Instrument};
+use yapping_core::l3gion_rust::{StdError, UUID};
 
-use crate::{coms::Coms, mongo_db::MongoDBClient, notification_manager::{NotificationManager, NotificationManagerMessage}};
+use crate::{codec::Codec, config::ServerConfig, http_api::{self, ApiState}, coms::Coms, login_guard::LoginGuard, metrics::Metrics, rate_limiter::RateLimiter, mongo_db::{MongoDB, MongoDBClient}, user_cache::UserCache, notification_manager::{NotificationManager, NotificationManagerMessage}};
 
-pub(crate) struct ServerManager {
+#[derive(Default)]
+struct ConnectionCounts {
+    total: usize,
+    by_address: HashMap<IpAddr, usize>,
+}
+
+// Held by a connection task for as long as it runs.
+struct ConnectionSlot {
+    connections: Arc<StdMutex<ConnectionCounts>>,
+    metrics: Arc<Metrics>,
+    address: IpAddr,
+}
+impl ConnectionSlot {
+    fn acquire(connections: &Arc<StdMutex<ConnectionCounts>>, metrics: &Arc<Metrics>, address: IpAddr, config: &ServerConfig) -> Option<Self> {
+        let mut counts = connections.lock().ok()?;
+        let from_address = counts.by_address.get(&address).copied().unwrap_or(0);
+        if counts.total >= config.max_connections || from_address >= config.max_connections_per_address {
+            metrics.connection_refused();
+            return None;
+        }
+
+        counts.total += 1;
+        counts.by_address.insert(address, from_address + 1);
+        metrics.connection_accepted(counts.total);
+
+        Some(Self {
+            connections: Arc::clone(connections),
+            metrics: Arc::clone(metrics),
+            address,
+        })
+    }
+}
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let Ok(mut counts) = self.connections.lock() else { return };
+
+        counts.total -= 1;
+        self.metrics.connection_closed(counts.total);
+        if let Some(from_address) = counts.by_address.get_mut(&self.address) {
+            *from_address -= 1;
+            if *from_address == 0 {
+                counts.by_address.remove(&self.address);
+            }
+        }
+    }
+}
+
+// Aborts the read task however the connection task ends, even on a panic.
+// That drops the last reference to its Coms, whose Drop marks the user offline.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub struct ServerManager {
+    config: ServerConfig,
+    rate_limiter: Arc<StdMutex<RateLimiter>>,
+    login_guard: Arc<StdMutex<LoginGuard>>,
+    metrics: Arc<Metrics>,
+    connections: Arc<StdMutex<ConnectionCounts>>,
     mongo_db_client: MongoDBClient,
     users_manager_sender: Sender<(UUID, NotificationManagerMessage)>,
 }
 impl ServerManager {
-    pub(crate) async fn new() -> Result<Self, StdError> {
-        let um = NotificationManager::new();
+    pub async fn new(config: ServerConfig) -> Result<Self, StdError> {
+        let metrics = Arc::new(Metrics::default());
+
+        let um = NotificationManager::new(Arc::clone(&metrics));
         let us = um.sender();
         um.start_recv();
 
+        let mongo_db_client = MongoDBClient::new(UserCache::new(config.user_cache_capacity, config.user_cache_ttl), Arc::clone(&metrics)).await?;
+        mongo_db_client.migrate(false).await?;
+        mongo_db_client.get_database().create_audit_indexes().await?;
+        Self::start_moderation_watch(mongo_db_client.get_database(), &config, us.clone());
+        Self::start_friend_request_sweeper(mongo_db_client.get_database(), &config);
+
+        let rate_limiter = Arc::new(StdMutex::new(RateLimiter::new(config.rate_limits.clone())));
+        let login_guard = Arc::new(StdMutex::new(LoginGuard::new(&config)));
+        Self::start_http_api(ApiState {
+            config: config.clone(),
+            rate_limiter: Arc::clone(&rate_limiter),
+            login_guard: Arc::clone(&login_guard),
+            metrics: Arc::clone(&metrics),
+            mongo_db: mongo_db_client.get_database(),
+            notification_manager_sender: us.clone(),
+        });
+
         Ok(Self {
-            mongo_db_client: MongoDBClient::new().await?,
+            rate_limiter,
+            login_guard,
+            metrics,
+            connections: Arc::new(StdMutex::new(ConnectionCounts::default())),
+            config,
+            mongo_db_client,
             users_manager_sender: us,
         })
     }
 
-    pub(crate) async fn run(&self) -> Result<(), StdError> {
+    pub async fn run(&self) -> Result<(), StdError> {
         let listener = TcpListener::bind("0.0.0.0:8080").await?; // TODO: No idea if this is safe at all.
         info!("Yapping server is now running!");
     
-        while let Ok((stream, _)) = listener.accept().await {
+        while let Ok((stream, address)) = listener.accept().await {
+            // Dropping the stream refuses the connection before any handshake work.
+            let Some(connection_slot) = ConnectionSlot::acquire(&self.connections, &self.metrics, address.ip(), &self.config) else {
+                warn!("Refused connection from {address}, too many connections");
+                continue;
+            };
+
+            let config = self.config.clone();
+            let rate_limiter = Arc::clone(&self.rate_limiter);
+            let login_guard = Arc::clone(&self.login_guard);
+            let metrics = Arc::clone(&self.metrics);
             let mongo_db = self.mongo_db_client.get_database();
             let users_manager_sender = self.users_manager_sender.clone();
 
+            // Everything logged for this connection carries its address, and its user once logged in.
+            let span = info_span!("connection", %address, user = field::Empty, tag = field::Empty);
+
             tokio::spawn(async move {
+                let _connection_slot = connection_slot;
                 info!("New connection task spawned!");
 
-                let ws_stream = match accept_async(stream).await {
+                let mut ws_config = WebSocketConfig::default();
+                ws_config.max_frame_size = Some(config.max_frame_size);
+                ws_config.max_message_size = Some(config.max_message_size);
+
+                // Bincode unless the client asks for another codec through its subprotocols.
+                let mut codec = Codec::default();
+                let negotiate_codec = |request: &Request, mut response: Response| -> Result<Response, ErrorResponse> {
+                    let requested = request.headers().get(SEC_WEBSOCKET_PROTOCOL).and_then(|header| header.to_str().ok());
+                    if let Some(requested_codec) = requested.and_then(Codec::from_subprotocols) {
+                        codec = requested_codec;
+                        response.headers_mut().insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(requested_codec.subprotocol()));
+                    }
+
+                    Ok(response)
+                };
+
+                let ws_stream = match accept_hdr_async_with_config(stream, negotiate_codec, Some(ws_config)).await {
                     Ok(stream) => stream,
                     Err(e) => {
                         error!("Handshake failed! {e}");
@@ -47,44 +162,178 @@ impl ServerManager {
                 let (write, mut read) = ws_stream.split();
 
                 let coms = Arc::new(Mutex::new(Coms::new(
+                    address,
+                    codec,
+                    config,
+                    rate_limiter,
+                    login_guard,
+                    metrics,
                     mongo_db, 
                     users_manager_sender,
                     write,
                 )));
                 let coms_read = Arc::clone(&coms);
                 
-                let read_taks = tokio::spawn(async move {
-                    while let Some(Ok(msg)) = read.next().await {
-                        match msg {
-                            TkMessage::Binary(msg) => {
-                                if let Err(e) = coms_read.lock().await.receive_msg(msg).await {
-                                    error!("In Coms::receive_msg: {e}");
+                let read_taks = AbortOnDrop(tokio::spawn(async move {
+                    while let Some(frame) = read.next().await {
+                        let mut coms = coms_read.lock().await;
+                        coms.record_activity();
+
+                        match frame {
+                            Ok(frame) => if let Err(e) = coms.receive_frame(frame).await {
+                                error!("In Coms::receive_frame: {e}");
+                            },
+                            Err(e) => {
+                                if let Err(e) = coms.receive_error(e).await {
+                                    error!("In Coms::receive_error: {e}");
                                 }
-                            }
-                            _ => ()
-                        };
+                                break;
+                            },
+                        }
+                        if coms.is_closed() {
+                            break;
+                        }
                     }
-                });
+                }.in_current_span()));
                 
                 loop {
                     if let Err(e) = coms.lock().await.update().await {
                         error!("In Coms::update()! {e}");
                     }
                     
-                    if read_taks.is_finished() {
+                    if read_taks.0.is_finished() || coms.lock().await.is_closed() {
                         break;
                     }
 
                     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
                 }
                 
+                read_taks.0.abort();
                 if let Err(e) = coms.lock().await.shutdown().await {
                     error!("In Coms::shutdown: {e}");
                 }
                 warn!("Connection taks ended!");
-            });
+            }.instrument(span));
         };
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+// Private
+impl ServerManager {
+    // Bans and suspensions are applied from outside the server (yapping_admin), so they are polled from the database.
+    fn start_moderation_watch(mongo_db: MongoDB, config: &ServerConfig, users_manager_sender: Sender<(UUID, NotificationManagerMessage)>) {
+        let interval = config.moderation_poll_interval;
+
+        tokio::spawn(async move {
+            info!("Moderation watch task spawned!");
+
+            let take_events = move || {
+                let mongo_db = mongo_db.clone();
+                async move { mongo_db.take_moderation_events().await }
+            };
+            watch_moderation(take_events, interval, users_manager_sender).await;
+        });
+    }
+
+    fn start_http_api(state: ApiState) {
+        let metrics_state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = http_api::serve_metrics(metrics_state).await {
+                error!("In ServerManager::start_http_api: {e}");
+            }
+        });
+
+        tokio::spawn(async move {
+            if let Err(e) = http_api::serve(state).await {
+                error!("In ServerManager::start_http_api: {e}");
+            }
+        });
+    }
+
+    fn start_friend_request_sweeper(mongo_db: MongoDB, config: &ServerConfig) {
+        let ttl = config.friend_request_ttl;
+        let interval = config.friend_request_sweep_interval;
+
+        tokio::spawn(async move {
+            info!("Friend request sweeper task spawned!");
+
+            loop {
+                match mongo_db.remove_expired_friend_requests(ttl).await {
+                    Ok(0) => (),
+                    Ok(removed) => info!("Removed {removed} expired friend requests"),
+                    Err(e) => error!("In ServerManager::start_friend_request_sweeper: {e}"),
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
+
+// An event is picked up at most one interval after it was stored, the NotificationManager then disconnects the user right away.
+async fn watch_moderation<F, Fut>(mut take_events: F, interval: Duration, users_manager_sender: Sender<(UUID, NotificationManagerMessage)>)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Vec<(String, String)>, StdError>>,
+{
+    loop {
+        match take_events().await {
+            Ok(events) => for (user_id, reason) in events {
+                if let Err(e) = users_manager_sender.send((UUID::default(), NotificationManagerMessage::DISCONNECT_USER(user_id, reason))).await {
+                    error!("In server_manager::watch_moderation: {e}");
+                }
+            },
+            Err(e) => error!("In server_manager::watch_moderation: {e}"),
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::time::Instant;
+
+    use crate::notification_manager::UserConnection;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn bans_disconnect_within_one_poll_interval() {
+        let interval = Duration::from_secs(1);
+        let manager = NotificationManager::new(Arc::new(Metrics::default()));
+        let sender = manager.sender();
+        manager.start_recv();
+
+        let user = UUID::generate();
+        let (notification_sender, _notification_receiver) = tokio::sync::mpsc::channel(1);
+        let (disconnect_sender, mut disconnect_receiver) = tokio::sync::mpsc::channel(1);
+        let connection = UserConnection { notification_sender, disconnect_sender };
+        sender.send((user, NotificationManagerMessage::NOTIFY_USER(user, vec![], HashSet::default(), connection))).await.unwrap();
+
+        // The worst case, the ban is stored right after a poll.
+        let banned_at = Arc::new(StdMutex::new(None));
+        let take_events = {
+            let banned_at = Arc::clone(&banned_at);
+            move || {
+                let events = match *banned_at.lock().unwrap() {
+                    Some(_) => vec![(user.to_string(), "Banned!".to_string())],
+                    None => vec![],
+                };
+                banned_at.lock().unwrap().get_or_insert_with(Instant::now);
+
+                async move { Ok(events) }
+            }
+        };
+        tokio::spawn(watch_moderation(take_events, interval, sender));
+
+        let reason = disconnect_receiver.recv().await.unwrap();
+        let banned_at = banned_at.lock().unwrap().unwrap();
+
+        assert_eq!(reason, "Banned!");
+        assert!(banned_at.elapsed() <= interval);
+    }
+}
@@ -0,0 +1,177 @@
+use std::{collections::{BTreeMap, HashMap}, time::{Duration, Instant}};
+
+use yapping_core::user::User;
+
+#[derive(Debug)]
+struct Entry<U> {
+    user: U,
+    inserted: Instant,
+    // Keys into UserCache::by_insertion and UserCache::by_use.
+    insertion: u64,
+    last_used: u64,
+}
+
+// Stripped user profiles by id, as stored in the database.
+// Entries expire after the ttl, and the least recently used one goes first when full.
+// Only writes from this process invalidate entries. yapping_admin only changes passwords and moderation state,
+// neither is part of a stripped user, anything else it changed would show once the entry expires.
+// Generic only so the tests don't need real users.
+#[derive(Debug)]
+pub struct UserCache<U = User> {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, Entry<U>>,
+    // Ids in insertion order, the first one expires first since they all share the ttl.
+    by_insertion: BTreeMap<u64, String>,
+    // Ids from the least to the most recently used.
+    by_use: BTreeMap<u64, String>,
+    uses: u64,
+}
+impl<U: Clone> UserCache<U> {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::default(),
+            by_insertion: BTreeMap::default(),
+            by_use: BTreeMap::default(),
+            uses: 0,
+        }
+    }
+
+    pub(crate) fn get(&mut self, id: &str) -> Option<U> {
+        self.uses += 1;
+
+        let entry = self.entries.get_mut(id)?;
+        if entry.inserted.elapsed() >= self.ttl {
+            self.remove(id);
+            return None;
+        }
+        self.by_use.remove(&entry.last_used);
+        self.by_use.insert(self.uses, id.to_string());
+        entry.last_used = self.uses;
+
+        Some(entry.user.clone())
+    }
+
+    pub(crate) fn insert(&mut self, id: String, user: U) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.uses += 1;
+
+        self.remove(&id);
+        if self.entries.len() >= self.capacity {
+            self.evict();
+        }
+        self.by_insertion.insert(self.uses, id.clone());
+        self.by_use.insert(self.uses, id.clone());
+        self.entries.insert(id, Entry {
+            user,
+            inserted: Instant::now(),
+            insertion: self.uses,
+            last_used: self.uses,
+        });
+    }
+
+    pub(crate) fn invalidate(&mut self, id: &str) {
+        self.remove(id);
+    }
+}
+// Private
+impl<U> UserCache<U> {
+    // The oldest entry when expired, the least recently used one otherwise.
+    fn evict(&mut self) {
+        let oldest = self.by_insertion.first_key_value()
+            .and_then(|(_, id)| self.entries.get(id).map(|entry| (id.clone(), entry.inserted)));
+
+        let evicted = match oldest {
+            Some((id, inserted)) if inserted.elapsed() >= self.ttl => Some(id),
+            _ => self.by_use.first_key_value().map(|(_, id)| id.clone()),
+        };
+
+        if let Some(evicted) = evicted {
+            self.remove(&evicted);
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.by_insertion.remove(&entry.insertion);
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_goes_first() {
+        let mut cache = UserCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        assert_eq!(cache.get("a"), Some(1));
+
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.get("b"), None);
+        assert_eq!(cache.get("a"), Some(1));
+        assert_eq!(cache.get("c"), Some(3));
+    }
+
+    #[test]
+    fn replacing_an_entry_evicts_nothing() {
+        let mut cache = UserCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        cache.insert("a".to_string(), 3);
+
+        assert_eq!(cache.get("a"), Some(3));
+        assert_eq!(cache.get("b"), Some(2));
+    }
+
+    #[test]
+    fn expired_entries_are_gone() {
+        let mut cache = UserCache::new(2, Duration::ZERO);
+        cache.insert("a".to_string(), 1);
+
+        assert_eq!(cache.get("a"), None);
+        assert!(cache.entries.is_empty());
+    }
+
+    #[test]
+    fn expired_entries_are_evicted_before_used_ones() {
+        let mut cache = UserCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        assert_eq!(cache.get("a"), Some(1));
+        cache.entries.get_mut("a").unwrap().inserted -= Duration::from_secs(60);
+
+        cache.insert("c".to_string(), 3);
+        assert_eq!(cache.get("a"), None);
+        assert_eq!(cache.get("b"), Some(2));
+    }
+
+    #[test]
+    fn invalidated_entries_leave_nothing_behind() {
+        let mut cache = UserCache::new(2, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+        cache.insert("b".to_string(), 2);
+        cache.invalidate("a");
+        cache.insert("c".to_string(), 3);
+
+        assert_eq!(cache.get("b"), Some(2));
+        assert_eq!(cache.get("c"), Some(3));
+        assert_eq!(cache.by_insertion.len(), 2);
+        assert_eq!(cache.by_use.len(), 2);
+    }
+
+    #[test]
+    fn zero_capacity_caches_nothing() {
+        let mut cache = UserCache::new(0, Duration::from_secs(60));
+        cache.insert("a".to_string(), 1);
+
+        assert_eq!(cache.get("a"), None);
+    }
+}